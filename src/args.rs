@@ -1,52 +1,248 @@
 use std::collections::HashSet;
 
-use clap::Parser;
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Parser};
+
+use crate::logger::Verbosity;
+use crate::WgetResult;
 
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 pub struct Args {
     /// URLs to download (space separated)
     #[arg(index = 1, num_args = 1..)]
     pub urls: Vec<String>,
 
     /// Input file containing URLs to download
-    #[arg(short = 'i')]
+    #[arg(short = 'i', long = "input-file")]
     pub input_file: Option<String>,
 
+    /// Treat the input file as HTML and download the links it contains
+    #[arg(short = 'F', long = "force-html")]
+    pub force_html: bool,
+
+    /// Resolve relative URLs in the input file against this URL
+    #[arg(long = "base")]
+    pub base: Option<String>,
+
     /// Save files under different names
-    #[arg(short = 'O')]
+    #[arg(short = 'O', long = "output-document")]
     pub output: Option<String>,
 
     /// Save files in a specific directory
-    #[arg(short = 'P')]
+    #[arg(short = 'P', long = "directory-prefix")]
     pub path: Option<String>,
 
+    /// Don't create directories, save every file in the current one (also -nd)
+    #[arg(long = "no-directories", overrides_with = "force_directories")]
+    pub no_directories: bool,
+
+    /// Create the host/path directories even for single downloads
+    #[arg(
+        short = 'x',
+        long = "force-directories",
+        overrides_with = "no_directories"
+    )]
+    pub force_directories: bool,
+
+    /// Don't create a directory per host (also -nH)
+    #[arg(long = "no-host-directories")]
+    pub no_host_directories: bool,
+
+    /// Put the files under a directory named after the protocol (http/, https/)
+    #[arg(long = "protocol-directories")]
+    pub protocol_directories: bool,
+
+    /// Ignore the first N directories of the remote path
+    #[arg(long = "cut-dirs", default_value_t = 0)]
+    pub cut_dirs: usize,
+
     /// Download in background
-    #[arg(short = 'B')]
+    #[arg(short = 'B', long = "background")]
     pub background: bool,
 
+    /// Log all messages to logfile
+    #[arg(short = 'o', long = "output-file", conflicts_with = "append_output")]
+    pub output_file: Option<String>,
+
+    /// Append all messages to logfile
+    #[arg(short = 'a', long = "append-output")]
+    pub append_output: Option<String>,
+
+    /// Quiet (no output)
+    #[arg(short = 'q', long = "quiet")]
+    pub quiet: bool,
+
+    /// Turn off verboseness, without being quiet (also -nv)
+    #[arg(long = "no-verbose", overrides_with = "verbose")]
+    pub no_verbose: bool,
+
+    /// Be verbose (this is the default)
+    #[arg(short = 'v', long = "verbose", overrides_with = "no_verbose")]
+    pub verbose: bool,
+
+    /// Print lots of debugging information
+    #[arg(short = 'd', long = "debug")]
+    pub debug: bool,
+
     /// Rate limit (e.g., "200k" or "2M")
     #[arg(long = "rate-limit")]
     pub rate_limit: Option<String>,
 
-    /// Mirror website
-    #[arg(long = "mirror")]
+    /// Skip files larger than this when mirroring (e.g., "50M" or "1G")
+    #[arg(long = "max-file-size")]
+    pub max_file_size: Option<String>,
+
+    /// Mirror website (recursive with infinite depth and timestamping)
+    #[arg(short = 'm', long = "mirror")]
     pub mirror: bool,
 
-    /// File types to reject (comma-separated)
+    /// Download recursively
+    #[arg(short = 'r', long = "recursive")]
+    pub recursive: bool,
+
+    /// Maximum recursion depth, "inf" or 0 for infinite (default 5)
+    #[arg(short = 'l', long = "level")]
+    pub level: Option<String>,
+
+    /// Go to foreign hosts when recursive
+    #[arg(short = 'H', long = "span-hosts")]
+    pub span_hosts: bool,
+
+    /// Comma-separated list of accepted domains (with -H)
+    #[arg(short = 'D', long = "domains")]
+    pub domains: Option<String>,
+
+    /// Comma-separated list of rejected domains
+    #[arg(long = "exclude-domains")]
+    pub exclude_domains: Option<String>,
+
+    /// Honor robots.txt and robots meta tags while crawling (on/off)
+    #[arg(long = "robots", value_name = "on|off", default_value_t = true, action = ArgAction::Set, value_parser = BoolishValueParser::new())]
+    pub robots: bool,
+
+    /// Number of URLs fetched concurrently while crawling
+    #[arg(long = "max-threads", default_value_t = 5)]
+    pub max_threads: usize,
+
+    /// Concurrent connections allowed to a single host while crawling
+    #[arg(long = "max-threads-per-host", default_value_t = 2)]
+    pub max_threads_per_host: usize,
+
+    /// Get all images, etc. needed to display the HTML pages
+    #[arg(short = 'p', long = "page-requisites")]
+    pub page_requisites: bool,
+
+    /// Don't re-retrieve files unless newer than the local copy
+    #[arg(short = 'N', long = "timestamping")]
+    pub timestamping: bool,
+
+    /// File types or name patterns to accept (comma-separated, e.g. "pdf,report-*")
+    #[arg(short = 'A', long = "accept")]
+    pub accept: Option<String>,
+
+    /// File types or name patterns to reject (comma-separated)
     #[arg(short = 'R', long = "reject")]
     pub reject: Option<String>,
 
-    /// Directories to exclude (comma-separated)
+    /// Regex matching accepted URLs
+    #[arg(long = "accept-regex")]
+    pub accept_regex: Option<String>,
+
+    /// Regex matching rejected URLs
+    #[arg(long = "reject-regex")]
+    pub reject_regex: Option<String>,
+
+    /// Ignore case when matching files and directories
+    #[arg(long = "ignore-case")]
+    pub ignore_case: bool,
+
+    /// Directories to exclude (comma-separated, wildcards allowed)
     #[arg(short = 'X', long = "exclude")]
     pub exclude: Option<String>,
 
+    /// Directories to include (comma-separated, wildcards allowed)
+    #[arg(short = 'I', long = "include")]
+    pub include: Option<String>,
+
+    /// Don't ascend to the parent directory (also -np)
+    #[arg(long = "no-parent")]
+    pub no_parent: bool,
+
     /// Convert links for offline viewing
     #[arg(long = "convert-links")]
     pub convert_links: bool,
+
+    /// Also sort query parameters and add the trailing slash of
+    /// extensionless paths when comparing URLs
+    #[arg(long = "canonicalize-urls")]
+    pub canonicalize_urls: bool,
+
+    /// Characters to escape in file names: unix, windows, ascii, lowercase,
+    /// uppercase or nocontrol (comma-separated)
+    #[arg(long = "restrict-file-names")]
+    pub restrict_file_names: Option<String>,
+
+    /// Append .html or .css to files whose name doesn't match their type
+    #[arg(short = 'E', long = "adjust-extension", alias = "html-extension")]
+    pub adjust_extension: bool,
+
+    /// Keep the original of each converted file as FILE.orig
+    #[arg(short = 'K', long = "backup-converted")]
+    pub backup_converted: bool,
+
+    /// Continue an interrupted crawl from the state saved in the output
+    /// directory
+    #[arg(long = "resume-crawl")]
+    pub resume_crawl: bool,
+
+    /// Save the body of HTTP error pages (404, 500...) when mirroring
+    #[arg(long = "content-on-error")]
+    pub content_on_error: bool,
+
+    /// Use this startup file instead of /etc/wgetrc and ~/.wgetrc
+    #[arg(long = "config", conflicts_with = "no_config")]
+    pub config: Option<String>,
+
+    /// Do not read any startup file
+    #[arg(long = "no-config")]
+    pub no_config: bool,
+
+    /// Execute a `.wgetrc`-style command (e.g. -e limit_rate=200k)
+    #[arg(short = 'e', long = "execute")]
+    pub execute: Vec<String>,
+}
+
+/// GNU wget spells some long options as multi-letter short flags (`-nv`),
+/// which clap cannot parse. They are rewritten to their long form first.
+pub fn expand_gnu_flags<I>(args: I) -> Vec<String>
+where
+    I: IntoIterator<Item = String>,
+{
+    args.into_iter()
+        .map(|arg| match arg.as_str() {
+            "-nv" => "--no-verbose".to_string(),
+            "-np" => "--no-parent".to_string(),
+            "-nH" => "--no-host-directories".to_string(),
+            "-nd" => "--no-directories".to_string(),
+            _ => arg,
+        })
+        .collect()
 }
 
 impl Args {
+    pub fn verbosity(&self) -> Verbosity {
+        if self.debug {
+            Verbosity::Debug
+        } else if self.quiet {
+            Verbosity::Quiet
+        } else if self.no_verbose {
+            Verbosity::NoVerbose
+        } else {
+            Verbosity::Verbose
+        }
+    }
+
     pub fn get_rejected_extensions(&self) -> HashSet<String> {
         self.reject
             .as_ref()
@@ -54,6 +250,13 @@ impl Args {
             .unwrap_or_default()
     }
 
+    pub fn get_accepted_extensions(&self) -> HashSet<String> {
+        self.accept
+            .as_ref()
+            .map(|a| a.split(',').map(|s| s.trim().to_string()).collect())
+            .unwrap_or_default()
+    }
+
     pub fn get_excluded_paths(&self) -> HashSet<String> {
         self.exclude
             .as_ref()
@@ -61,10 +264,139 @@ impl Args {
             .unwrap_or_default()
     }
 
+    pub fn get_included_paths(&self) -> HashSet<String> {
+        self.include
+            .as_ref()
+            .map(|i| i.split(',').map(|s| s.trim().to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get_domains(&self) -> HashSet<String> {
+        self.domains
+            .as_ref()
+            .map(|d| d.split(',').map(|s| s.trim().to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get_excluded_domains(&self) -> HashSet<String> {
+        self.exclude_domains
+            .as_ref()
+            .map(|d| d.split(',').map(|s| s.trim().to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether the URLs are crawled rather than downloaded as single files.
+    pub fn is_crawl(&self) -> bool {
+        self.mirror || self.recursive || self.page_requisites
+    }
+
+    /// Recursion depth limit, `None` meaning infinite. `-p` on its own only
+    /// fetches the given pages (depth 0) and their requisites.
+    pub fn max_depth(&self) -> Result<Option<u32>, String> {
+        let depth = match self.level.as_deref() {
+            Some("inf") | Some("0") => None,
+            Some(level) => Some(
+                level
+                    .parse()
+                    .map_err(|_| format!("invalid recursion depth '{}'", level))?,
+            ),
+            None if self.mirror => None,
+            None => Some(5),
+        };
+        if !self.recursive && !self.mirror {
+            return Ok(Some(0));
+        }
+        Ok(depth)
+    }
+
+    /// `--rate-limit` in bytes per second.
+    pub fn rate_limit_bytes(&self) -> WgetResult<Option<u64>> {
+        match &self.rate_limit {
+            Some(limit) => Ok(Some(parse_size(limit).ok_or_else(|| {
+                format!(
+                    "--rate-limit: invalid rate '{}', use bytes or k, M or G",
+                    limit
+                )
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    /// `--max-file-size` in bytes.
+    pub fn max_file_size_bytes(&self) -> WgetResult<Option<u64>> {
+        match &self.max_file_size {
+            Some(size) => Ok(Some(parse_size(size).ok_or_else(|| {
+                format!(
+                    "--max-file-size: invalid size '{}', use bytes or k, M or G",
+                    size
+                )
+            })?)),
+            None => Ok(None),
+        }
+    }
+
     pub fn validate(&self) -> Result<(), String> {
         if self.urls.is_empty() && self.input_file.is_none() {
-            return Err("wget: missing URL\nUsage: wget [OPTION]... [URL]...\n\nTry `wget --help` for more options.".into());
+            return Err("missing URL\nUsage: wget [OPTION]... [URL]...\n\nTry `wget --help` for more options.".into());
         }
+        self.max_depth()?;
         Ok(())
     }
 }
+
+/// A size such as `500`, `200k`, `2M` or `1G`, in bytes (powers of 1000).
+fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let multiplier = match unit.to_lowercase().as_str() {
+        "" => 1,
+        "k" => 1000,
+        "m" => 1000 * 1000,
+        "g" => 1000 * 1000 * 1000,
+        _ => return None,
+    };
+    number.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_size, Args};
+    use clap::Parser;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("500"), Some(500));
+        assert_eq!(parse_size("200k"), Some(200_000));
+        assert_eq!(parse_size("2M"), Some(2_000_000));
+        assert_eq!(parse_size(" 1g "), Some(1_000_000_000));
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("k"), None);
+        assert_eq!(parse_size("2x"), None);
+        assert_eq!(parse_size("1.5M"), None);
+        assert_eq!(parse_size("99999999999999999999"), None);
+        assert_eq!(parse_size("99999999999G"), None);
+    }
+
+    #[test]
+    fn recursion_depth() {
+        let depth = |args: &[&str]| Args::parse_from(args).max_depth();
+        assert_eq!(depth(&["wget", "-r", "u"]), Ok(Some(5)));
+        assert_eq!(depth(&["wget", "-r", "-l", "inf", "u"]), Ok(None));
+        assert_eq!(depth(&["wget", "-m", "u"]), Ok(None));
+        assert_eq!(depth(&["wget", "-p", "-l", "3", "u"]), Ok(Some(0)));
+        assert!(Args::parse_from(["wget", "-r", "-l", "abc", "u"])
+            .validate()
+            .is_err());
+        assert!(Args::parse_from(["wget", "-l", "abc", "u"])
+            .validate()
+            .is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use crate::WgetResult;
+use std::path::Path;
+
+/// Detaches the process from the terminal. The parent prints the child's
+/// PID and exits; the child continues in a new session with its standard
+/// streams pointed at `/dev/null`, so everything must go through the logger.
+///
+/// Must be called before the tokio runtime is started: forking a process
+/// that already has worker threads is not safe.
+#[cfg(unix)]
+pub fn detach(log_path: Option<&Path>) -> WgetResult<()> {
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the process is still single threaded at this point
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    if pid > 0 {
+        println!("Continuing in background, pid {}.", pid);
+        if let Some(path) = log_path {
+            println!("Output will be written to \"{}\"", path.display());
+        }
+        std::process::exit(0);
+    }
+
+    // child: become session leader so closing the terminal doesn't kill us
+    if unsafe { libc::setsid() } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let dev_null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    let fd = dev_null.as_raw_fd();
+    for target in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(fd, target) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Without `fork` we can only keep running in the foreground, with the
+/// output already redirected to the log file.
+#[cfg(not(unix))]
+pub fn detach(log_path: Option<&Path>) -> WgetResult<()> {
+    if let Some(path) = log_path {
+        println!("Output will be written to \"{}\"", path.display());
+    }
+    Ok(())
+}
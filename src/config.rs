@@ -0,0 +1,276 @@
+use crate::args::expand_gnu_flags;
+use crate::{Args, WgetResult};
+use clap::Parser;
+use std::env;
+use std::path::{Path, PathBuf};
+
+const SYSTEM_WGETRC: &str = "/etc/wgetrc";
+
+/// Parses the command line on top of the startup files.
+///
+/// Settings are applied in this order, each one overriding the previous:
+/// `/etc/wgetrc`, `~/.wgetrc` (or `$WGETRC`), `-e` commands, and finally
+/// the flags given on the command line. `--config FILE` replaces both
+/// startup files and `--no-config` skips them.
+pub fn load_args() -> WgetResult<Args> {
+    let argv = expand_gnu_flags(env::args());
+    let cli = Args::parse_from(&argv);
+    let files = if cli.no_config {
+        Vec::new()
+    } else {
+        startup_files(&cli)?
+    };
+    apply_settings(argv, &cli, &files)
+}
+
+/// Applies the startup `files` in order, then the `-e` commands of `cli`,
+/// then `argv` itself.
+fn apply_settings(argv: Vec<String>, cli: &Args, files: &[PathBuf]) -> WgetResult<Args> {
+    // a later setting of the same key replaces the earlier one
+    let mut settings: Vec<(String, String, String)> = Vec::new();
+    let mut set = |key: String, value: String, origin: String| {
+        settings.retain(|(k, _, _)| *k != key);
+        settings.push((key, value, origin));
+    };
+
+    for path in files {
+        for (line, key, value) in read_file(path)? {
+            set(key, value, format!("{} at line {}", path.display(), line));
+        }
+    }
+    for command in &cli.execute {
+        let (key, value) = parse_line(command)
+            .ok_or_else(|| format!("invalid command '{}' in -e, expected key = value", command))?;
+        set(key, value, "-e".to_string());
+    }
+
+    let mut full_argv = vec![argv[0].clone()];
+    for (key, value, origin) in &settings {
+        match to_flags(key, value)? {
+            Some(flags) => full_argv.extend(flags),
+            // the distribution's wgetrc is full of GNU settings we don't
+            // implement (passive_ftp...), only complain about the user's own
+            None if origin == "-e" => return Err(format!("unknown command '{}' in -e", key).into()),
+            None if !origin.starts_with(SYSTEM_WGETRC) => {
+                eprintln!("wget: ignoring unknown command '{}' in {}", key, origin)
+            }
+            None => {}
+        }
+    }
+    full_argv.extend(argv.into_iter().skip(1));
+
+    Ok(Args::parse_from(full_argv))
+}
+
+fn startup_files(cli: &Args) -> WgetResult<Vec<PathBuf>> {
+    if let Some(path) = &cli.config {
+        let path = PathBuf::from(path);
+        if !path.exists() {
+            return Err(format!("cannot read config file {}", path.display()).into());
+        }
+        return Ok(vec![path]);
+    }
+
+    let mut files = vec![PathBuf::from(SYSTEM_WGETRC)];
+    if let Ok(path) = env::var("WGETRC") {
+        files.push(PathBuf::from(path));
+    } else if let Ok(home) = env::var("HOME") {
+        files.push(PathBuf::from(home).join(".wgetrc"));
+    }
+    Ok(files.into_iter().filter(|p| p.exists()).collect())
+}
+
+/// Returns the `(line number, key, value)` commands of a startup file.
+fn read_file(path: &Path) -> WgetResult<Vec<(usize, String, String)>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut commands = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        // ignore empty lines and comments
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line) {
+            Some((key, value)) => commands.push((index + 1, key, value)),
+            None => {
+                return Err(
+                    format!("syntax error in {} at line {}", path.display(), index + 1).into(),
+                )
+            }
+        }
+    }
+
+    Ok(commands)
+}
+
+/// Splits a `key = value` line. Keys are case-insensitive and dashes or
+/// underscores are ignored, so `limit_rate`, `limit-rate` and `LimitRate`
+/// are the same command.
+fn parse_line(line: &str) -> Option<(String, String)> {
+    let (key, value) = line.split_once('=')?;
+    let key: String = key
+        .trim()
+        .chars()
+        .filter(|c| *c != '_' && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect();
+    if key.is_empty() {
+        return None;
+    }
+    Some((key, value.trim().to_string()))
+}
+
+fn parse_bool(key: &str, value: &str) -> WgetResult<bool> {
+    match value.to_lowercase().as_str() {
+        "on" | "yes" | "true" | "1" => Ok(true),
+        "off" | "no" | "false" | "0" => Ok(false),
+        _ => Err(format!("{}: invalid boolean '{}', use on or off", key, value).into()),
+    }
+}
+
+/// Maps a wgetrc command onto the equivalent command-line flags, or `None`
+/// if the command is not supported.
+fn to_flags(key: &str, value: &str) -> WgetResult<Option<Vec<String>>> {
+    let with_value = |flag: &str| Ok(Some(vec![format!("{}={}", flag, value)]));
+    let switch = |flag: &str| -> WgetResult<Option<Vec<String>>> {
+        Ok(Some(if parse_bool(key, value)? {
+            vec![flag.to_string()]
+        } else {
+            Vec::new()
+        }))
+    };
+
+    match key {
+        "limitrate" | "ratelimit" => with_value("--rate-limit"),
+        "maxfilesize" => with_value("--max-file-size"),
+        "dirprefix" => with_value("--directory-prefix"),
+        "dirstruct" => Ok(Some(vec![if parse_bool(key, value)? {
+            "--force-directories".to_string()
+        } else {
+            "--no-directories".to_string()
+        }])),
+        "addhostdir" => Ok(Some(if parse_bool(key, value)? {
+            Vec::new()
+        } else {
+            vec!["--no-host-directories".to_string()]
+        })),
+        "protocoldirectories" => switch("--protocol-directories"),
+        "cutdirs" => with_value("--cut-dirs"),
+        "outputdocument" => with_value("--output-document"),
+        "input" => with_value("--input-file"),
+        "base" => with_value("--base"),
+        "logfile" => with_value("--output-file"),
+        "accept" => with_value("--accept"),
+        "reject" => with_value("--reject"),
+        "acceptregex" => with_value("--accept-regex"),
+        "rejectregex" => with_value("--reject-regex"),
+        "ignorecase" => switch("--ignore-case"),
+        "excludedirectories" => with_value("--exclude"),
+        "includedirectories" => with_value("--include"),
+        "noparent" => switch("--no-parent"),
+        "background" => switch("--background"),
+        "mirror" => switch("--mirror"),
+        "recursive" => switch("--recursive"),
+        "reclevel" => with_value("--level"),
+        "timestamping" => switch("--timestamping"),
+        "pagerequisites" => switch("--page-requisites"),
+        "spanhosts" => switch("--span-hosts"),
+        "domains" => with_value("--domains"),
+        "excludedomains" => with_value("--exclude-domains"),
+        "robots" => Ok(Some(vec![format!("--robots={}", parse_bool(key, value)?)])),
+        "maxthreads" => with_value("--max-threads"),
+        "maxthreadsperhost" => with_value("--max-threads-per-host"),
+        "convertlinks" => switch("--convert-links"),
+        "backupconverted" => switch("--backup-converted"),
+        "contentonerror" => switch("--content-on-error"),
+        "resumecrawl" => switch("--resume-crawl"),
+        "adjustextension" | "htmlextension" => switch("--adjust-extension"),
+        "restrictfilenames" => with_value("--restrict-file-names"),
+        "canonicalizeurls" => switch("--canonicalize-urls"),
+        "forcehtml" => switch("--force-html"),
+        "quiet" => switch("--quiet"),
+        "debug" => switch("--debug"),
+        "verbose" => Ok(Some(vec![if parse_bool(key, value)? {
+            "--verbose".to_string()
+        } else {
+            "--no-verbose".to_string()
+        }])),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(key: &str, value: &str) -> Option<Vec<String>> {
+        to_flags(key, value).unwrap()
+    }
+
+    #[test]
+    fn keys_ignore_case_dashes_and_underscores() {
+        let expected = Some(("limitrate".to_string(), "200k".to_string()));
+        assert_eq!(parse_line("limit_rate = 200k"), expected);
+        assert_eq!(parse_line("limit-rate=200k"), expected);
+        assert_eq!(parse_line("  LimitRate =  200k "), expected);
+        assert_eq!(
+            parse_line("accept = a=b"),
+            Some(("accept".into(), "a=b".into()))
+        );
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert_eq!(parse_line("recursive"), None);
+        assert_eq!(parse_line(" = on"), None);
+    }
+
+    #[test]
+    fn commands_become_flags() {
+        assert_eq!(
+            flags("limitrate", "200k"),
+            Some(vec!["--rate-limit=200k".into()])
+        );
+        assert_eq!(flags("recursive", "on"), Some(vec!["--recursive".into()]));
+        assert_eq!(flags("recursive", "off"), Some(vec![]));
+        assert_eq!(
+            flags("addhostdir", "off"),
+            Some(vec!["--no-host-directories".into()])
+        );
+        assert_eq!(flags("verbose", "no"), Some(vec!["--no-verbose".into()]));
+        assert_eq!(flags("robots", "0"), Some(vec!["--robots=false".into()]));
+        assert_eq!(flags("passiveftp", "on"), None);
+        assert!(to_flags("recursive", "maybe").is_err());
+    }
+
+    #[test]
+    fn later_settings_win() {
+        let dir = env::temp_dir().join(format!("wget-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let system = dir.join("system");
+        let user = dir.join("user");
+        std::fs::write(&system, "# system\nlimit_rate = 1k\nrecursive = on\n").unwrap();
+        std::fs::write(&user, "limit_rate = 2k\nreclevel = 2\n").unwrap();
+
+        let rate = |argv: &[&str]| {
+            let argv: Vec<String> = argv.iter().map(|a| a.to_string()).collect();
+            let cli = Args::parse_from(&argv);
+            let args = apply_settings(argv, &cli, &[system.clone(), user.clone()]).unwrap();
+            assert!(args.recursive);
+            assert_eq!(args.level.as_deref(), Some("2"));
+            args.rate_limit
+        };
+        assert_eq!(rate(&["wget", "u"]).as_deref(), Some("2k"));
+        assert_eq!(
+            rate(&["wget", "-e", "limitrate=3k", "u"]).as_deref(),
+            Some("3k")
+        );
+        assert_eq!(
+            rate(&["wget", "-e", "limitrate=3k", "--rate-limit", "4k", "u"]).as_deref(),
+            Some("4k")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use regex::{Captures, Regex};
+use std::sync::LazyLock;
+
+/// `url(...)` with or without quotes, and the `@import "..."` form that has
+/// no `url()`.
+static REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?i)url\(\s*(?:"([^"]*)"|'([^']*)'|([^'"()\s]*))\s*\)|@import\s+(?:"([^"]*)"|'([^']*)')"#,
+    )
+    .unwrap()
+});
+
+/// The reference held by one match, whichever alternative matched.
+fn reference<'a>(caps: &Captures<'a>) -> Option<regex::Match<'a>> {
+    (1..=5).find_map(|group| caps.get(group))
+}
+
+/// Inline `data:` URIs and empty references are not links.
+fn is_link(reference: &str) -> bool {
+    !reference.is_empty() && !reference.starts_with("data:") && !reference.starts_with('#')
+}
+
+/// Returns the `url(...)` and `@import` references of a stylesheet, in
+/// order and as written.
+pub fn extract_urls(css: &str) -> Vec<String> {
+    REFERENCE
+        .captures_iter(css)
+        .filter_map(|caps| reference(&caps))
+        .map(|m| m.as_str().to_string())
+        .filter(|reference| is_link(reference))
+        .collect()
+}
+
+/// Replaces every reference for which `convert` returns a new value,
+/// leaving the quotes and the rest of the stylesheet untouched.
+pub fn rewrite_urls(css: &str, mut convert: impl FnMut(&str) -> Option<String>) -> String {
+    let mut output = String::with_capacity(css.len());
+    let mut last = 0;
+
+    for caps in REFERENCE.captures_iter(css) {
+        let Some(m) = reference(&caps) else {
+            continue;
+        };
+        if !is_link(m.as_str()) {
+            continue;
+        }
+        if let Some(new) = convert(m.as_str()) {
+            output.push_str(&css[last..m.start()]);
+            output.push_str(&new);
+            last = m.end();
+        }
+    }
+
+    output.push_str(&css[last..]);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_and_unquoted_urls() {
+        let css = r#"a { background: url("a.png") } b { background: URL( 'b.png' ) }
+            c { background: url(c.png) } d { background: url( ) }"#;
+        assert_eq!(extract_urls(css), ["a.png", "b.png", "c.png"]);
+    }
+
+    #[test]
+    fn imports() {
+        let css = r#"@import "base.css"; @import 'print.css' print; @import url(x.css);"#;
+        assert_eq!(extract_urls(css), ["base.css", "print.css", "x.css"]);
+    }
+
+    #[test]
+    fn data_uris_and_fragments_are_skipped() {
+        let css = r#"a { mask: url(#m); background: url("data:image/png;base64,AAAA") }
+            b { background: url(b.png) }"#;
+        assert_eq!(extract_urls(css), ["b.png"]);
+    }
+
+    #[test]
+    fn rewrite_keeps_quotes_and_the_rest() {
+        let css = r#"@import 'a.css';
+a { background: url( "img/a.png" ) } /* url(b.png) */ c { x: url(data:,x) }"#;
+        let rewritten = rewrite_urls(css, |url| match url {
+            "a.css" => Some("../a.css".to_string()),
+            "img/a.png" => Some("../img/a.png".to_string()),
+            _ => None,
+        });
+        assert_eq!(
+            rewritten,
+            r#"@import '../a.css';
+a { background: url( "../img/a.png" ) } /* url(b.png) */ c { x: url(data:,x) }"#
+        );
+    }
+}
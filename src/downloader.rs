@@ -1,101 +1,67 @@
-use crate::{mirror::WebsiteMirror, utils, Args, WgetResult};
+use crate::filename::{DirectoryLayout, FileNameRules};
+use crate::input::{self, InputEntry};
+use crate::{mirror::WebsiteMirror, utils, Args, Logger, Verbosity, WgetResult};
 use chrono::Local;
 use futures_util::StreamExt;
 use indicatif::MultiProgress;
-use reqwest;
+use reqwest::header::IF_MODIFIED_SINCE;
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
 use std::env;
 use std::path::PathBuf;
 use std::time::Instant;
 use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::AsyncWriteExt;
 use tokio::task;
+use url::Url;
 
+#[derive(Clone)]
 pub struct Downloader {
     args: Args,
     client: reqwest::Client,
-    output_file: Option<File>,
-}
-
-impl Clone for Downloader {
-    fn clone(&self) -> Self {
-        Downloader {
-            args: self.args.clone(),
-            client: self.client.clone(),
-            output_file: None, // ignore output_file in the copy
-        }
-    }
+    logger: Logger,
 }
 
 impl Downloader {
-    pub async fn new(args: Args) -> WgetResult<Self> {
-        let output_file = if args.background {
-            Some(File::create("wget-log").await?)
-        } else {
-            None
-        };
-
+    pub fn new(args: Args, logger: Logger) -> WgetResult<Self> {
         Ok(Self {
             args,
-            client: reqwest::Client::new(),
-            output_file,
+            client: utils::build_client(&logger)?,
+            logger,
         })
     }
 
-    async fn logln(&mut self, message: &str) -> WgetResult<()> {
-        if let Some(file) = &mut self.output_file {
-            file.write_all(format!("{}\n", message).as_bytes()).await?;
-            file.flush().await?;
-        } else {
-            println!("{}", message);
-        }
-        Ok(())
-    }
-
-    async fn log(&mut self, message: &str) -> WgetResult<()> {
-        if let Some(file) = &mut self.output_file {
-            file.write_all(format!("{}", message).as_bytes()).await?;
-            file.flush().await?;
-        } else {
-            print!("{}", message);
-        }
-        Ok(())
-    }
-
     pub async fn download_all(&mut self) -> WgetResult<()> {
-        if self.args.background {
-            println!("Output will be written to \"wget-log\"");
-        }
-
-        if self.args.mirror {
+        if self.args.is_crawl() {
             self.mirror_websites().await?;
         } else {
             let start_time = Local::now();
-            self.logln(&format!(
+            self.logger.logln(&format!(
                 "start at {}",
                 start_time.format("%Y-%m-%d %H:%M:%S")
-            ))
-            .await?;
+            ));
 
-            let m = if !self.args.background {
+            let m = if self.logger.shows_progress() {
                 Some(MultiProgress::new())
             } else {
                 None
             };
 
-            let rate_limit = self.parse_rate_limit()?;
-            //println!("rate_limit: {:?}", rate_limit);
+            let rate_limit = self.args.rate_limit_bytes()?;
+            self.logger.debug(&format!("rate_limit: {:?}", rate_limit));
 
             if let Some(input_file) = &self.args.input_file {
-                let urls = Self::read_urls_from_file(input_file).await?;
+                let entries = input::read_input(input_file, &self.args, &self.client).await?;
                 let mut tasks = Vec::new();
 
-                for url in urls {
+                for entry in entries {
                     let mut downloader = self.clone(); // clone `Self` for each task
-                    let rate_limit = rate_limit.clone();
 
                     let task = task::spawn(async move {
-                        if let Err(e) = downloader.download_file(&url, rate_limit, None).await {
-                            eprintln!("Failed to download {}: {}", url, e);
+                        if let Err(e) = downloader.download_file(&entry, rate_limit, None).await {
+                            downloader
+                                .logger
+                                .error(&format!("Failed to download {}: {}", entry.url, e));
                         }
                     });
                     tasks.push(task);
@@ -109,28 +75,23 @@ impl Downloader {
             // créer un clone des URLs pour éviter le problème de borrowing
             let urls: Vec<String> = self.args.urls.clone();
 
-            for (_, url) in urls.iter().enumerate() {
-                self.download_file(url, rate_limit, m.as_ref()).await?;
+            for url in urls.iter() {
+                let entry = InputEntry::new(url.as_str());
+                self.download_file(&entry, rate_limit, m.as_ref()).await?;
             }
 
-            self.logln(&format!(
+            self.logger.logln(&format!(
                 "finished at {}",
                 Local::now().format("%Y-%m-%d %H:%M:%S")
-            ))
-            .await?;
+            ));
         }
         Ok(())
     }
 
     async fn mirror_websites(&self) -> WgetResult<()> {
         for url in &self.args.urls {
-            println!("Mirroring website: {}", url);
-            let mut mirror = WebsiteMirror::new(
-                url.clone(),
-                self.args.get_rejected_extensions(),
-                self.args.get_excluded_paths(),
-                self.args.convert_links,
-            )?;
+            self.logger.logln(&format!("Mirroring website: {}", url));
+            let mut mirror = WebsiteMirror::new(url.clone(), &self.args, self.logger.clone())?;
             mirror.start().await?;
         }
         Ok(())
@@ -138,68 +99,86 @@ impl Downloader {
 
     async fn download_file(
         &mut self,
-        url: &str,
+        entry: &InputEntry,
         rate_limit: Option<u64>,
         progress_bars: Option<&MultiProgress>,
     ) -> WgetResult<()> {
+        let url = entry.url.as_str();
         if self.args.input_file.is_none() {
-            self.log(&format!("sending request, awaiting response... "))
-                .await?;
+            self.logger.log("sending request, awaiting response... ");
         }
 
-        let response = self.client.get(url).send().await?;
+        // -N asks the server for the file only if it changed since the copy
+        // an earlier run saved under the same name
+        let mut request = self.client.get(url);
+        if self.args.timestamping {
+            let previous = self.destination(entry, "")?;
+            if let Some(modified) = utils::local_modified_time(&previous).await {
+                request = request.header(IF_MODIFIED_SINCE, utils::format_http_date(modified));
+            }
+        }
+        let request = request.build()?;
+        self.logger.debug_request(&request);
+        let response = self.client.execute(request).await?;
+        self.logger.debug_response(&response);
 
         let status = response.status();
         if self.args.input_file.is_none() {
-            self.logln(&format!("status {}", status)).await?;
+            self.logger.logln(&format!("status {}", status));
         }
 
+        if status == StatusCode::NOT_MODIFIED {
+            self.logger.logln(&format!(
+                "File \"{}\" not modified on server, omitting download.",
+                self.destination(entry, "")?.display()
+            ));
+            return Ok(());
+        }
         if !status.is_success() {
             return Err(format!("Failed with status: {}", status).into());
         }
 
         let total_size = response.content_length().unwrap_or(0);
-        self.logln(&format!(
+        self.logger.logln(&format!(
             "content size: {} [~{}]",
             total_size,
             utils::format_size(total_size)
-        ))
-        .await?;
-
-        let mut filename = match &self.args.output {
-            Some(name) => format!("{}", name),
-            None => utils::extract_filename_from_url(url),
-        };
-
-        let mut dest_path = match &self.args.path {
-            Some(p) => PathBuf::from(p).join(&filename),
-            None => PathBuf::from(&filename),
-        };
-
-        // check if a file with this name already exists
-        let mut unique_index = 1;
-        while dest_path.exists() {
-            filename = match &self.args.output {
-                Some(name) => {
-                    utils::add_suffix_before_extension(&name, &format!("_{}", unique_index))
-                }
-                None => utils::add_suffix_before_extension(
-                    &utils::extract_filename_from_url(url),
+        ));
+
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        let mut dest_path = self.destination(entry, content_type)?;
+        let last_modified = utils::last_modified(&response);
+
+        // check if a file with this name already exists, -N replaces it
+        if !self.args.timestamping {
+            let first_path = dest_path.clone();
+            let name = first_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            let mut unique_index = 1;
+            while dest_path.exists() {
+                dest_path = first_path.with_file_name(utils::add_suffix_before_extension(
+                    &name,
                     &format!("_{}", unique_index),
-                ),
-            };
-
-            dest_path = match &self.args.path {
-                Some(p) => PathBuf::from(p).join(&filename),
-                None => PathBuf::from(&filename),
-            };
-
-            unique_index += 1;
+                ));
+                unique_index += 1;
+            }
         }
+        let filename = dest_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
 
         if self.args.input_file.is_none() {
-            self.logln(&format!("saving file to: {}", dest_path.display()))
-                .await?;
+            self.logger
+                .logln(&format!("saving file to: {}", dest_path.display()));
         }
 
         let pb = if let Some(mp) = progress_bars {
@@ -212,18 +191,14 @@ impl Downloader {
 
         let mut downloaded: u64 = 0;
 
-        if dest_path.starts_with("~") {
-            if let Ok(home_dir) = env::var("HOME") {
-                let dest_path_str = dest_path.to_string_lossy();
-                dest_path = PathBuf::from(format!(
-                    "{}/{}",
-                    home_dir,
-                    dest_path_str.trim_start_matches('~')
-                ));
+        if let Some(parent) = dest_path.parent() {
+            if !parent.as_os_str().is_empty() {
+                tokio::fs::create_dir_all(parent).await?;
             }
         }
 
         let mut file = File::create(&dest_path).await?;
+        let mut hasher = entry.sha256.as_ref().map(|_| Sha256::new());
         let mut stream = response.bytes_stream();
         let mut last_check = Instant::now();
         let mut bytes_since_last_check: u64 = 0;
@@ -240,17 +215,17 @@ impl Downloader {
             let elapsed = last_check.elapsed().as_secs_f64();
 
             if let Some(max_speed) = rate_limit {
-                // println!(
-                //     "bytes_since_last_check: {}; max_speed: {}",
-                //     bytes_since_last_check, max_speed
-                // );
-                if bytes_since_last_check >= max_speed - 2 * chunk_size {
+                if bytes_since_last_check >= max_speed.saturating_sub(2 * chunk_size) {
                     if elapsed >= delta_time {
                         speed = bytes_since_last_check as f64;
                         bytes_since_last_check = 0;
                         last_check = Instant::now();
                     } else {
                         let sleep_time = delta_time - elapsed;
+                        self.logger.debug(&format!(
+                            "rate limit: {} bytes since last check, sleeping {:.2}s",
+                            bytes_since_last_check, sleep_time
+                        ));
                         tokio::time::sleep(tokio::time::Duration::from_secs_f64(sleep_time)).await;
                     }
                 }
@@ -263,6 +238,9 @@ impl Downloader {
             }
 
             file.write_all(&chunk).await?;
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&chunk);
+            }
 
             if let Some(pb) = &pb {
                 let percentage = (downloaded as f64 / total_size as f64) * 100.0;
@@ -279,58 +257,97 @@ impl Downloader {
             pb.finish_with_message("completed");
         }
 
-        self.logln(&format!("\nDownloaded [{}]", url)).await?;
-        Ok(())
-    }
+        if let (Some(expected), Some(hasher)) = (&entry.sha256, hasher) {
+            let actual = format!("{:x}", hasher.finalize());
+            if *expected != actual {
+                drop(file);
+                tokio::fs::remove_file(&dest_path).await?;
+                return Err(format!(
+                    "sha256 mismatch for {}: expected {}, got {}",
+                    dest_path.display(),
+                    expected,
+                    actual
+                )
+                .into());
+            }
+        }
 
-    fn parse_rate_limit(&self) -> WgetResult<Option<u64>> {
-        if let Some(limit) = &self.args.rate_limit {
-            let mut chars = limit.chars();
-            let mut num_str = String::new();
-            let mut unit = String::new();
-
-            while let Some(c) = chars.next() {
-                if c.is_digit(10) {
-                    num_str.push(c);
-                } else {
-                    unit.push(c);
-                    unit.extend(chars);
-                    break;
-                }
+        // keep the server's date so the next -N run can compare against it
+        if self.args.timestamping {
+            if let Some(modified) = last_modified {
+                file.into_std().await.set_modified(modified)?;
             }
+        }
 
-            let number: u64 = num_str.parse()?;
+        self.logger.logln(&format!("\nDownloaded [{}]", url));
+        if self.logger.verbosity() == Verbosity::NoVerbose {
+            self.logger.notice(&format!(
+                "{} URL:{} [{}] -> \"{}\"",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                url,
+                downloaded,
+                dest_path.display()
+            ));
+        }
+        Ok(())
+    }
 
-            let bytes_per_sec = match unit.to_lowercase().as_str() {
-                "k" => number * 1000,
-                "m" => number * 1000 * 1000,
-                _ => return Err("Invalid rate limit unit (use k or M)".into()),
-            };
+    /// Path of a single download: the `out=` of its `-i` line or `-O`,
+    /// else the name from the URL with the `-E` extension for its
+    /// `content_type`, under `-P` and the `dir=` of the line.
+    fn destination(&self, entry: &InputEntry, content_type: &str) -> WgetResult<PathBuf> {
+        // per-entry options from the input file win over -O
+        let output = entry.output.as_ref().or(self.args.output.as_ref());
+        let mut dest_dir = match &self.args.path {
+            Some(p) => PathBuf::from(p),
+            None => PathBuf::new(),
+        };
+        if let Some(dir) = &entry.dir {
+            dest_dir.push(dir);
+        }
 
-            Ok(Some(bytes_per_sec))
-        } else {
-            Ok(None)
+        // the file is named after the URL, under its host and directories
+        // with -x
+        let relative_path = self.url_path(&entry.url)?;
+        if output.is_none() {
+            if let Some(parent) = relative_path.parent() {
+                dest_dir.push(parent);
+            }
         }
-    }
+        let mut url_filename = relative_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("index.html")
+            .to_string();
+        if self.args.adjust_extension {
+            if let Some(extension) = utils::adjusted_extension(&url_filename, content_type) {
+                url_filename.push_str(extension);
+            }
+        }
+        let dest_path = dest_dir.join(output.unwrap_or(&url_filename));
 
-    async fn read_urls_from_file(file_path: &str) -> WgetResult<Vec<String>> {
-        // Open the specified file
-        let file = File::open(file_path).await?;
-        let reader = BufReader::new(file);
-
-        // Initializes a vector to store URLs
-        let mut urls = Vec::new();
-
-        // read the line file by line
-        let mut lines = reader.lines();
-        while let Some(line) = lines.next_line().await? {
-            let trimmed = line.trim();
-            // ignore the empty lines
-            if !trimmed.is_empty() {
-                urls.push(trimmed.to_string());
+        if dest_path.starts_with("~") {
+            if let Ok(home_dir) = env::var("HOME") {
+                let dest_path_str = dest_path.to_string_lossy();
+                return Ok(PathBuf::from(format!(
+                    "{}/{}",
+                    home_dir,
+                    dest_path_str.trim_start_matches('~')
+                )));
             }
         }
+        Ok(dest_path)
+    }
 
-        Ok(urls)
+    /// Where a single download goes relative to `-P`: its file name, or the
+    /// host and path directories with `-x`.
+    fn url_path(&self, url: &str) -> WgetResult<PathBuf> {
+        let url = Url::parse(url)?;
+        let mut path = url.path().to_string();
+        if path.ends_with('/') {
+            path.push_str("index.html");
+        }
+        let file_path = FileNameRules::from_args(&self.args)?.file_path(&path[1..], url.query());
+        Ok(DirectoryLayout::from_args(&self.args).relative_path(&url, &file_path))
     }
 }
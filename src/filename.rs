@@ -0,0 +1,315 @@
+use crate::{Args, WgetResult};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use sha2::{Digest, Sha256};
+use std::path::{Component, Path, PathBuf};
+use url::Url;
+
+/// Longest file name component we write. Most filesystems stop at 255
+/// bytes, the rest is left for the `.html` and `.orig` suffixes added later.
+const MAX_COMPONENT_LEN: usize = 200;
+
+/// Characters of a local file name that mean something else in a link.
+const LINK_ESCAPES: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`');
+
+#[derive(Clone, Copy, PartialEq)]
+enum Case {
+    Unchanged,
+    Lower,
+    Upper,
+}
+
+/// `--restrict-file-names`: which characters of a URL may appear in the
+/// names of the files it is saved to.
+#[derive(Clone, Copy)]
+pub struct FileNameRules {
+    /// also escape `\ | : ? " * < >`, and put `@` before the query
+    windows: bool,
+    /// escape non-ASCII characters
+    ascii: bool,
+    /// escape control characters (not with `nocontrol`)
+    controls: bool,
+    case: Case,
+}
+
+impl FileNameRules {
+    pub fn from_args(args: &Args) -> WgetResult<Self> {
+        let mut rules = Self {
+            windows: cfg!(windows),
+            ascii: false,
+            controls: true,
+            case: Case::Unchanged,
+        };
+        let Some(modes) = &args.restrict_file_names else {
+            return Ok(rules);
+        };
+
+        for mode in modes.split(',').map(|mode| mode.trim().to_lowercase()) {
+            match mode.as_str() {
+                "unix" => rules.windows = false,
+                "windows" => rules.windows = true,
+                "ascii" => rules.ascii = true,
+                "nocontrol" => rules.controls = false,
+                "lowercase" => rules.case = Case::Lower,
+                "uppercase" => rules.case = Case::Upper,
+                _ => {
+                    return Err(format!(
+                        "--restrict-file-names: invalid mode '{}', use unix, windows, ascii, lowercase, uppercase or nocontrol",
+                        mode
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(rules)
+    }
+
+    /// The relative file path for a URL path (without its leading `/`) and
+    /// query: `list` and `page=1` give `list?page=1`, or `list@page=1` with
+    /// the Windows rules. Path segments are percent-decoded, then whatever
+    /// the rules forbid is escaped back as `%XX` and overlong names are
+    /// shortened.
+    pub fn file_path(&self, path: &str, query: Option<&str>) -> PathBuf {
+        let mut components: Vec<String> = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| self.escape(&percent_decode_str(segment).decode_utf8_lossy()))
+            .collect();
+
+        if let Some(query) = query {
+            let separator = if self.windows { '@' } else { '?' };
+            let last = components.pop().unwrap_or_default();
+            components.push(format!("{}{}{}", last, separator, self.escape(query)));
+        }
+
+        components.into_iter().map(shorten).collect()
+    }
+
+    fn escape(&self, name: &str) -> String {
+        let name = match self.case {
+            Case::Unchanged => name.to_string(),
+            Case::Lower => name.to_lowercase(),
+            Case::Upper => name.to_uppercase(),
+        };
+
+        // a decoded `%2E%2E` must not climb out of the mirror
+        if name == "." || name == ".." {
+            return name.replace('.', "%2E");
+        }
+
+        let mut escaped = String::with_capacity(name.len());
+        for c in name.chars() {
+            let forbidden = c == '/'
+                || c == '\0'
+                || (self.controls && (c.is_ascii_control() || ('\u{80}'..='\u{9f}').contains(&c)))
+                || (self.windows && "\\|:?\"*<>".contains(c))
+                || (self.ascii && !c.is_ascii());
+            if forbidden {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    escaped.push_str(&format!("%{:02X}", byte));
+                }
+            } else {
+                escaped.push(c);
+            }
+        }
+        escaped
+    }
+}
+
+/// Which directories the files of a URL go into: `-nH`, `--cut-dirs`,
+/// `-nd`/`-x` and `--protocol-directories`. Paths are relative to `-P`.
+pub struct DirectoryLayout {
+    /// `-nd`: every file directly in the prefix directory
+    flat: bool,
+    host_dirs: bool,
+    protocol_dirs: bool,
+    cut_dirs: usize,
+}
+
+impl DirectoryLayout {
+    /// Recursive downloads keep the site's structure unless `-nd`, single
+    /// downloads are flat unless `-x`.
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            flat: if args.is_crawl() {
+                args.no_directories
+            } else {
+                !args.force_directories
+            },
+            host_dirs: !args.no_host_directories,
+            protocol_dirs: args.protocol_directories,
+            cut_dirs: args.cut_dirs,
+        }
+    }
+
+    /// Where to save `url`, given its file path from [`FileNameRules`]:
+    /// `example.com/docs/a.html`, or `docs/a.html` with `-nH`, `a.html` with
+    /// `--cut-dirs=1`, `http/example.com/docs/a.html` with
+    /// `--protocol-directories`.
+    pub fn relative_path(&self, url: &Url, file_path: &Path) -> PathBuf {
+        let components: Vec<Component> = file_path.components().collect();
+        let Some((file, dirs)) = components.split_last() else {
+            return PathBuf::new();
+        };
+        if self.flat {
+            return PathBuf::from(file.as_os_str());
+        }
+
+        let mut path = PathBuf::new();
+        if self.protocol_dirs {
+            path.push(url.scheme());
+        }
+        if self.host_dirs {
+            path.push(url.host_str().unwrap_or_default());
+        }
+        for dir in dirs.iter().skip(self.cut_dirs) {
+            path.push(dir);
+        }
+        path.push(file);
+        path
+    }
+}
+
+/// Cuts a component longer than `MAX_COMPONENT_LEN`, keeping its
+/// extension. A hash of the full name keeps shortened names distinct.
+fn shorten(component: String) -> String {
+    if component.len() <= MAX_COMPONENT_LEN {
+        return component;
+    }
+
+    let extension = component
+        .rfind('.')
+        .map(|pos| &component[pos..])
+        .filter(|extension| extension.len() <= 16)
+        .unwrap_or("");
+    let hash: String = Sha256::digest(component.as_bytes())
+        .iter()
+        .take(4)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    let mut end = MAX_COMPONENT_LEN - extension.len() - hash.len() - 1;
+    while !component.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}~{}{}", &component[..end], hash, extension)
+}
+
+/// A component of a local path as written in a converted link, where
+/// `?`, `#` or `%` in the file name must not be taken for URL syntax.
+pub fn link_component(component: &str) -> String {
+    utf8_percent_encode(component, LINK_ESCAPES).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn rules(modes: &str) -> FileNameRules {
+        FileNameRules::from_args(&Args::parse_from([
+            "wget",
+            "--restrict-file-names",
+            modes,
+            "http://a.com/",
+        ]))
+        .unwrap()
+    }
+
+    fn file_path(rules: &FileNameRules, path: &str, query: Option<&str>) -> String {
+        rules.file_path(path, query).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn query_separator() {
+        assert_eq!(
+            file_path(&rules("unix"), "list", Some("page=1")),
+            "list?page=1"
+        );
+        assert_eq!(
+            file_path(&rules("windows"), "list", Some("page=1")),
+            "list@page=1"
+        );
+        assert_eq!(
+            file_path(&rules("windows"), "a.php", Some("q=a:b?")),
+            "a.php@q=a%3Ab%3F"
+        );
+    }
+
+    #[test]
+    fn decoded_segments_are_escaped_back() {
+        let unix = rules("unix");
+        assert_eq!(
+            file_path(&unix, "docs/caf%C3%A9%20menu.pdf", None),
+            "docs/café menu.pdf"
+        );
+        assert_eq!(file_path(&unix, "a%2Fb", None), "a%2Fb");
+        assert_eq!(file_path(&unix, "a/%2E%2E/b", None), "a/%2E%2E/b");
+        assert_eq!(file_path(&unix, "%2E/b", None), "%2E/b");
+        assert_eq!(file_path(&unix, "a%01b", None), "a%01b");
+        assert_eq!(
+            file_path(&rules("unix,nocontrol"), "a%01b", None),
+            "a\u{1}b"
+        );
+        assert_eq!(file_path(&rules("windows"), "a%3Ab%7Cc", None), "a%3Ab%7Cc");
+    }
+
+    #[test]
+    fn ascii_and_case() {
+        assert_eq!(
+            file_path(&rules("unix,ascii"), "caf%C3%A9", None),
+            "caf%C3%A9"
+        );
+        assert_eq!(
+            file_path(&rules("unix,lowercase"), "Caf%C3%89", None),
+            "café"
+        );
+        assert_eq!(
+            file_path(&rules("unix,uppercase"), "a.html", None),
+            "A.HTML"
+        );
+        // the case applies before escaping, escapes stay uppercase
+        assert_eq!(
+            file_path(&rules("ascii,lowercase"), "%C3%89", None),
+            "%C3%A9"
+        );
+    }
+
+    #[test]
+    fn invalid_mode() {
+        let args = Args::parse_from(["wget", "--restrict-file-names", "dos", "http://a.com/"]);
+        assert!(FileNameRules::from_args(&args).is_err());
+    }
+
+    #[test]
+    fn long_names_are_shortened() {
+        let long = format!("{}.html", "a".repeat(300));
+        let short = shorten(long.clone());
+        assert!(short.len() <= MAX_COMPONENT_LEN);
+        assert!(short.ends_with(".html"));
+        let (_, suffix) = short.rsplit_once('~').unwrap();
+        assert_eq!(suffix.len(), 8 + ".html".len());
+
+        let other = shorten(format!("{}b.html", "a".repeat(300)));
+        assert_ne!(short, other);
+        assert_eq!(shorten("short.html".to_string()), "short.html");
+    }
+
+    #[test]
+    fn shortening_keeps_whole_characters() {
+        let short = shorten("é".repeat(150));
+        assert!(short.len() <= MAX_COMPONENT_LEN);
+        assert!(short.starts_with('é'));
+        // an overlong "extension" is not kept
+        let short = shorten(format!("a.{}", "b".repeat(300)));
+        assert!(short.len() <= MAX_COMPONENT_LEN);
+    }
+}
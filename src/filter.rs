@@ -0,0 +1,114 @@
+use crate::{utils, Args, WgetResult};
+use regex::{Regex, RegexBuilder};
+use std::path::Path;
+use url::Url;
+
+/// File name and URL filters: `-A`, `-R`, `--accept-regex`, `--reject-regex`.
+pub struct UrlFilter {
+    accept: Vec<String>,
+    reject: Vec<String>,
+    accept_regex: Option<Regex>,
+    reject_regex: Option<Regex>,
+    ignore_case: bool,
+}
+
+impl UrlFilter {
+    pub fn from_args(args: &Args) -> WgetResult<Self> {
+        let case = |s: String| {
+            if args.ignore_case {
+                s.to_lowercase()
+            } else {
+                s
+            }
+        };
+        let regex = |pattern: &Option<String>| -> WgetResult<Option<Regex>> {
+            Ok(match pattern {
+                Some(pattern) => Some(
+                    RegexBuilder::new(pattern)
+                        .case_insensitive(args.ignore_case)
+                        .build()?,
+                ),
+                None => None,
+            })
+        };
+
+        Ok(Self {
+            accept: args
+                .get_accepted_extensions()
+                .into_iter()
+                .map(case)
+                .collect(),
+            reject: args
+                .get_rejected_extensions()
+                .into_iter()
+                .map(case)
+                .collect(),
+            accept_regex: regex(&args.accept_regex)?,
+            reject_regex: regex(&args.reject_regex)?,
+            ignore_case: args.ignore_case,
+        })
+    }
+
+    /// Whether the file behind this URL should be kept.
+    pub fn is_wanted(&self, url: &Url) -> bool {
+        let name = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or("");
+        let name = if self.ignore_case {
+            name.to_lowercase()
+        } else {
+            name.to_string()
+        };
+
+        if !self.accept.is_empty() && !self.accept.iter().any(|a| name_matches(a, &name)) {
+            return false;
+        }
+        if self.reject.iter().any(|r| name_matches(r, &name)) {
+            return false;
+        }
+        if let Some(regex) = &self.accept_regex {
+            if !regex.is_match(url.as_str()) {
+                return false;
+            }
+        }
+        if let Some(regex) = &self.reject_regex {
+            if regex.is_match(url.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn ignore_case(&self) -> bool {
+        self.ignore_case
+    }
+}
+
+/// `-A`/`-R` entries with wildcards (`report-*`, `*.pdf`) are matched
+/// against the whole file name, plain entries (`pdf`) against its extension.
+fn name_matches(entry: &str, name: &str) -> bool {
+    if utils::has_wildcards(entry) {
+        utils::glob_match(entry, name)
+    } else {
+        Path::new(name).extension().and_then(|e| e.to_str()) == Some(entry)
+    }
+}
+
+/// URLs that may well be HTML pages. When rejected by the filters they are
+/// still fetched so their links can be followed, then not kept.
+pub fn looks_like_page(url: &Url) -> bool {
+    matches!(
+        utils::url_extension(url)
+            .map(|e| e.to_lowercase())
+            .as_deref(),
+        None | Some("html")
+            | Some("htm")
+            | Some("xhtml")
+            | Some("shtml")
+            | Some("php")
+            | Some("asp")
+            | Some("aspx")
+            | Some("jsp")
+    )
+}
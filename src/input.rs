@@ -0,0 +1,190 @@
+use crate::filter::UrlFilter;
+use crate::{links, Args, WgetResult};
+use reqwest::Client;
+use std::collections::HashSet;
+use tokio::io::AsyncReadExt;
+use url::Url;
+
+/// One download from the command line or from an `-i` list.
+#[derive(Debug, Clone, Default)]
+pub struct InputEntry {
+    pub url: String,
+    /// `out=` : file name, overrides `-O`
+    pub output: Option<String>,
+    /// `dir=` : sub-directory, relative to `-P`
+    pub dir: Option<String>,
+    /// `sha256=` : expected checksum of the downloaded file
+    pub sha256: Option<String>,
+}
+
+impl InputEntry {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Reads the `-i` list, `-` meaning standard input and an `http(s)://`
+/// source being fetched first.
+///
+/// Each line holds a URL optionally followed by `key=value` options:
+///
+/// ```text
+/// # nightly builds
+/// https://example.com/build.zip  out=latest.zip  sha256=9f86d0...  dir=nightly/
+/// relative/file.txt              # resolved against --base
+/// ```
+///
+/// With `--force-html` (or a remote source served as `text/html`) the input
+/// is an HTML page instead and every link in it is downloaded.
+pub async fn read_input(source: &str, args: &Args, client: &Client) -> WgetResult<Vec<InputEntry>> {
+    let mut base = args.base.as_deref().map(Url::parse).transpose()?;
+    let mut is_html = args.force_html;
+
+    let content = if source == "-" {
+        let mut content = String::new();
+        tokio::io::stdin().read_to_string(&mut content).await?;
+        content
+    } else if source.starts_with("http://") || source.starts_with("https://") {
+        let response = client.get(source).send().await?.error_for_status()?;
+        is_html |= response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("text/html"));
+        // links in a remote page are relative to the page itself
+        base = base.or_else(|| Some(response.url().clone()));
+        response.text().await?
+    } else {
+        tokio::fs::read_to_string(source).await?
+    };
+
+    if is_html {
+        html_entries(&content, base.as_ref(), args)
+    } else {
+        let name = if source == "-" { "stdin" } else { source };
+        list_entries(&content, name, base.as_ref())
+    }
+}
+
+fn list_entries(content: &str, name: &str, base: Option<&Url>) -> WgetResult<Vec<InputEntry>> {
+    let mut entries = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        if let Some(entry) =
+            parse_line(line, base).map_err(|e| format!("{}:{}: {}", name, index + 1, e))?
+        {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Links of an HTML page that pass `-A`/`-R` and the regex filters, in
+/// document order and without duplicates. A `<base href>` in the page
+/// takes over from `--base`, and relative links are dropped when there is
+/// no base to resolve them against, like GNU wget does.
+fn html_entries(html: &str, base: Option<&Url>, args: &Args) -> WgetResult<Vec<InputEntry>> {
+    let filter = UrlFilter::from_args(args)?;
+    let mut seen = HashSet::new();
+    let page_base = links::base_href(html)
+        .and_then(|href| resolve_url(&href, base).ok())
+        .and_then(|href| Url::parse(&href).ok());
+    let base = page_base.as_ref().or(base);
+
+    Ok(links::extract_links(html)
+        .iter()
+        .filter_map(|link| resolve_url(&link.url, base).ok())
+        .filter_map(|link| Url::parse(&link).ok())
+        .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+        .filter(|url| filter.is_wanted(url))
+        .map(String::from)
+        .filter(|url| seen.insert(url.clone()))
+        .map(InputEntry::new)
+        .collect())
+}
+
+fn parse_line(line: &str, base: Option<&Url>) -> Result<Option<InputEntry>, String> {
+    // everything after a `#` token is a comment
+    let mut tokens = line
+        .split_whitespace()
+        .take_while(|token| !token.starts_with('#'));
+
+    let url = match tokens.next() {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+    let mut entry = InputEntry::new(resolve_url(url, base)?);
+
+    for option in tokens {
+        let (key, value) = option
+            .split_once('=')
+            .ok_or_else(|| format!("invalid option '{}', expected key=value", option))?;
+        match key {
+            "out" => entry.output = Some(value.to_string()),
+            "dir" => entry.dir = Some(value.to_string()),
+            "sha256" => entry.sha256 = Some(value.to_lowercase()),
+            _ => return Err(format!("unknown option '{}'", key)),
+        }
+    }
+
+    Ok(Some(entry))
+}
+
+/// Absolute URLs are kept as they are, relative ones need `--base`.
+pub fn resolve_url(url: &str, base: Option<&Url>) -> Result<String, String> {
+    match Url::parse(url) {
+        Ok(_) => Ok(url.to_string()),
+        Err(url::ParseError::RelativeUrlWithoutBase) => match base {
+            Some(base) => base
+                .join(url)
+                .map(String::from)
+                .map_err(|e| format!("cannot resolve '{}': {}", url, e)),
+            None => Err(format!("relative URL '{}' needs --base", url)),
+        },
+        Err(e) => Err(format!("invalid URL '{}': {}", url, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blank_and_comment_lines() {
+        assert!(parse_line("", None).unwrap().is_none());
+        assert!(parse_line("   ", None).unwrap().is_none());
+        assert!(parse_line("# a comment", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn url_with_options_and_comment() {
+        let entry = parse_line(
+            "https://a.com/f.zip out=g.zip dir=sub sha256=ABC # trailing comment",
+            None,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(entry.url, "https://a.com/f.zip");
+        assert_eq!(entry.output.as_deref(), Some("g.zip"));
+        assert_eq!(entry.dir.as_deref(), Some("sub"));
+        assert_eq!(entry.sha256.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn relative_urls_need_a_base() {
+        assert!(parse_line("files/a.zip", None).is_err());
+        let base = Url::parse("https://a.com/dl/").unwrap();
+        let entry = parse_line("files/a.zip", Some(&base)).unwrap().unwrap();
+        assert_eq!(entry.url, "https://a.com/dl/files/a.zip");
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(parse_line("https://a.com/ out", None).is_err());
+        assert!(parse_line("https://a.com/ speed=1", None).is_err());
+    }
+}
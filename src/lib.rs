@@ -1,21 +1,121 @@
 use std::error::Error;
 
 pub mod args;
+pub mod background;
+pub mod config;
+pub mod css;
 pub mod downloader;
+pub mod filename;
+pub mod filter;
+pub mod input;
+pub mod limiter;
+pub mod links;
+pub mod logger;
 pub mod mirror;
+pub mod robots;
 
 pub use args::Args;
 pub use downloader::Downloader;
+pub use logger::{Logger, Verbosity};
 
 // Type alias pour simplifier la gestion des erreurs
 pub type WgetResult<T> = Result<T, Box<dyn Error>>;
 
 // Fonctions utilitaires qui peuvent être utilisées dans tout le projet
 pub mod utils {
+    use crate::{Logger, WgetResult};
+    use chrono::{DateTime, Utc};
     use indicatif::{ProgressBar, ProgressStyle};
+    use reqwest::header::LAST_MODIFIED;
+    use reqwest::redirect::Policy;
+    use reqwest::{Client, Response};
+    use std::path::Path;
+    use std::sync::{Mutex, MutexGuard};
+    use std::time::SystemTime;
+    use url::Url;
 
     pub fn extract_filename_from_url(url: &str) -> String {
-        url.split('/').last().unwrap_or("download").to_string()
+        url.split('/').next_back().unwrap_or("download").to_string()
+    }
+
+    /// Extension of the last path segment, e.g. `pdf` for `/docs/a.pdf?x=1`.
+    pub fn url_extension(url: &Url) -> Option<String> {
+        let last = url.path_segments()?.next_back()?;
+        Path::new(last)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(String::from)
+    }
+
+    pub fn has_wildcards(pattern: &str) -> bool {
+        pattern.contains(['*', '?', '['])
+    }
+
+    /// Shell-style matching of the whole text: `*` is any sequence of
+    /// characters (including `/`), `?` any single character and `[...]` a
+    /// set such as `[a-z]` or `[!0-9]`.
+    pub fn glob_match(pattern: &str, text: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let text: Vec<char> = text.chars().collect();
+        let (mut p, mut t) = (0, 0);
+        // position of the last `*` and of the text it currently stands for
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        while t < text.len() {
+            if p < pattern.len() && pattern[p] == '*' {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            if p < pattern.len() {
+                if let Some((matched, next)) = match_one(&pattern[p..], text[t]) {
+                    if matched {
+                        p += next;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+            // mismatch: let the last `*` swallow one more character
+            match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            }
+        }
+
+        pattern[p..].iter().all(|c| *c == '*')
+    }
+
+    /// Matches one text character against the start of the pattern,
+    /// returning whether it matched and how many pattern characters it used.
+    fn match_one(pattern: &[char], c: char) -> Option<(bool, usize)> {
+        match pattern[0] {
+            '?' => Some((true, 1)),
+            '[' if pattern.iter().skip(2).any(|x| *x == ']') => {
+                let end = pattern.iter().skip(2).position(|x| *x == ']')? + 2;
+                let (negate, set) = match pattern[1] {
+                    '!' | '^' => (true, &pattern[2..end]),
+                    _ => (false, &pattern[1..end]),
+                };
+                let mut found = false;
+                let mut i = 0;
+                while i < set.len() {
+                    if i + 2 < set.len() && set[i + 1] == '-' {
+                        found |= set[i] <= c && c <= set[i + 2];
+                        i += 3;
+                    } else {
+                        found |= set[i] == c;
+                        i += 1;
+                    }
+                }
+                Some((found != negate, end + 1))
+            }
+            literal => Some((literal == c, 1)),
+        }
     }
 
     pub fn format_size(size: u64) -> String {
@@ -37,6 +137,76 @@ pub mod utils {
         pb
     }
 
+    /// Locks a mutex shared between tasks. A poisoned lock only means
+    /// another task panicked while holding it, the data is still used.
+    pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        mutex.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub const MAX_REDIRECTS: usize = 10;
+
+    /// HTTP client shared by single downloads and the mirror. Redirects are
+    /// followed like reqwest's default policy but every hop is logged in debug.
+    pub fn build_client(logger: &Logger) -> WgetResult<Client> {
+        let logger = logger.clone();
+        let policy = Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            if let Some(previous) = attempt.previous().last() {
+                logger.debug(&format!(
+                    "redirect {} {} -> {}",
+                    attempt.status(),
+                    previous,
+                    attempt.url()
+                ));
+            }
+            attempt.follow()
+        });
+        Ok(Client::builder().redirect(policy).build()?)
+    }
+
+    /// HTTP client that returns redirects instead of following them, for
+    /// callers that must check each hop.
+    pub fn build_manual_redirect_client() -> WgetResult<Client> {
+        Ok(Client::builder().redirect(Policy::none()).build()?)
+    }
+
+    /// Modification time of a local file, `None` when it doesn't exist.
+    pub async fn local_modified_time(path: &Path) -> Option<SystemTime> {
+        tokio::fs::metadata(path).await.ok()?.modified().ok()
+    }
+
+    /// `If-Modified-Since` value for `-N`.
+    pub fn format_http_date(time: SystemTime) -> String {
+        DateTime::<Utc>::from(time)
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    }
+
+    /// The server's `Last-Modified` date, given to the saved file with `-N`.
+    pub fn last_modified(response: &Response) -> Option<SystemTime> {
+        response
+            .headers()
+            .get(LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+            .map(SystemTime::from)
+    }
+
+    /// `-E`: the extension to append to a file name that doesn't match its
+    /// `Content-Type`, `.html` for pages and `.css` for stylesheets.
+    pub fn adjusted_extension(filename: &str, content_type: &str) -> Option<&'static str> {
+        let name = filename.to_lowercase();
+        if content_type.contains("text/html") || content_type.contains("application/xhtml+xml") {
+            (!name.ends_with(".html") && !name.ends_with(".htm")).then_some(".html")
+        } else if content_type.contains("text/css") {
+            (!name.ends_with(".css")).then_some(".css")
+        } else {
+            None
+        }
+    }
+
     pub fn add_suffix_before_extension(filename: &str, suffix: &str) -> String {
         if let Some(pos) = filename.rfind('.') {
             let (name, ext) = filename.split_at(pos);
@@ -45,4 +215,36 @@ pub mod utils {
             format!("{}{}", filename, suffix)
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::glob_match;
+
+        #[test]
+        fn glob_literals_and_question_mark() {
+            assert!(glob_match("/docs", "/docs"));
+            assert!(!glob_match("/docs", "/docs/"));
+            assert!(glob_match("/v?", "/v2"));
+            assert!(!glob_match("/v?", "/v"));
+        }
+
+        #[test]
+        fn glob_star_crosses_slashes() {
+            assert!(glob_match("/docs/*/old", "/docs/a/b/old"));
+            assert!(glob_match("*.pdf", "/papers/x.pdf"));
+            assert!(glob_match("/a*b*c", "/aXbYbZc"));
+            assert!(!glob_match("/a*b*c", "/aXcYb"));
+            assert!(glob_match("**", ""));
+        }
+
+        #[test]
+        fn glob_sets() {
+            assert!(glob_match("/v[0-9]", "/v7"));
+            assert!(!glob_match("/v[0-9]", "/vx"));
+            assert!(glob_match("/v[!0-9]", "/vx"));
+            assert!(glob_match("/[ab]c", "/bc"));
+            // an unclosed bracket is a literal
+            assert!(glob_match("/[a", "/[a"));
+        }
+    }
 }
@@ -0,0 +1,33 @@
+use crate::utils;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// `--rate-limit` shared by every download of a crawl, so the limit holds
+/// for the total whatever the number of workers. Each chunk books its share
+/// of the bandwidth and waits until the chunks booked before it are sent.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    /// when everything booked so far will have been sent at the limit
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for the turn of `bytes` just received.
+    pub async fn consume(&self, bytes: u64) {
+        let start = {
+            let mut next_free = utils::lock(&self.next_free);
+            let start = (*next_free).max(Instant::now());
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+}
@@ -0,0 +1,446 @@
+use crate::{css, WgetResult};
+use lol_html::html_content::{ContentType, Element};
+use lol_html::{element, text, RewriteStrSettings};
+use scraper::{Html, Selector};
+use std::cell::RefCell;
+use std::ops::Range;
+
+/// A link as written in the document, not resolved yet.
+pub struct Link {
+    pub url: String,
+    /// `<a rel="nofollow">`
+    pub nofollow: bool,
+    /// needed to render the page (image, stylesheet, script, CSS `url()`)
+    /// rather than navigation to another page
+    pub requisite: bool,
+}
+
+/// Where links live in a page: a selector and the attribute holding the
+/// link. `textContent` is the CSS of a `<style>` element, `style` an inline
+/// style, `srcset`/`imagesrcset` a list of candidates and `xlink:href` the
+/// SVG link attribute, with or without its namespace.
+const LINK_ATTRIBUTES: &[(&str, &str)] = &[
+    ("a[href]", "href"),
+    ("link[href]", "href"),
+    ("img[src]", "src"),
+    ("script[src]", "src"),
+    ("link[imagesrcset]", "imagesrcset"),
+    ("img[srcset]", "srcset"),
+    ("source[srcset]", "srcset"),
+    ("source[src]", "src"),
+    ("video[src]", "src"),
+    ("video[poster]", "poster"),
+    ("audio[src]", "src"),
+    ("track[src]", "src"),
+    ("iframe[src]", "src"),
+    ("frame[src]", "src"),
+    ("embed[src]", "src"),
+    ("object[data]", "data"),
+    // a redirect-only page is useless without its target, so like GNU
+    // wget the meta refresh target is treated as a requisite
+    ("meta[http-equiv][content]", "content"),
+    // SVG links, `href` in SVG 2 or `xlink:href` before it
+    ("svg image", "xlink:href"),
+    ("svg use", "xlink:href"),
+    ("svg a:not([href])", "xlink:href"),
+    ("style", "textContent"),
+    ("[style]", "style"), // Pour les styles inline
+];
+
+/// Returns every link of a page: `href`/`src` attributes, `srcset`
+/// candidates, media and embedded documents, SVG `xlink:href` and the
+/// `url(...)` and `@import` references of `<style>` blocks and inline `style`
+/// attributes. Links are not resolved, callers join them with the base they
+/// need.
+pub fn extract_links(html: &str) -> Vec<Link> {
+    let document = Html::parse_document(html);
+    let mut links = Vec::new();
+
+    for (selector, attr) in LINK_ATTRIBUTES {
+        let selector = Selector::parse(selector).unwrap();
+        for element in document.select(&selector) {
+            // Traitement spécial pour les balises style et attributs style
+            if *attr == "textContent" || *attr == "style" {
+                let css_content = if *attr == "textContent" {
+                    element.inner_html()
+                } else {
+                    element.value().attr(attr).unwrap_or("").to_string()
+                };
+
+                // Chercher toutes les URLs dans le CSS
+                links.extend(css::extract_urls(&css_content).into_iter().map(|url| Link {
+                    url,
+                    nofollow: false,
+                    requisite: true,
+                }));
+            } else {
+                // Traitement normal des autres attributs
+                let urls: Vec<String> = match *attr {
+                    "srcset" | "imagesrcset" => {
+                        let srcset = element.value().attr(attr).unwrap_or("");
+                        srcset_spans(srcset)
+                            .into_iter()
+                            .map(|span| srcset[span].to_string())
+                            .collect()
+                    }
+                    "content" if is_refresh(element.value().attr("http-equiv")) => {
+                        let content = element.value().attr(attr).unwrap_or("");
+                        refresh_span(content)
+                            .map(|span| content[span].to_string())
+                            .into_iter()
+                            .collect()
+                    }
+                    "content" => Vec::new(),
+                    // `attr()` only sees attributes without a namespace
+                    "xlink:href" => element
+                        .value()
+                        .attrs()
+                        .find(|(name, _)| *name == "href")
+                        .map(|(_, value)| vec![value.to_string()])
+                        .unwrap_or_default(),
+                    _ => element
+                        .value()
+                        .attr(attr)
+                        .map(String::from)
+                        .into_iter()
+                        .collect(),
+                };
+                let rel = element.value().attr("rel").unwrap_or("").to_lowercase();
+                let has_rel = |value: &str| rel.split_whitespace().any(|r| r == value);
+                let (nofollow, requisite) = match element.value().name() {
+                    "a" => (has_rel("nofollow"), false),
+                    "link" => (
+                        false,
+                        ["stylesheet", "icon", "preload", "modulepreload", "manifest"]
+                            .iter()
+                            .any(|value| has_rel(value)),
+                    ),
+                    _ => (false, true),
+                };
+                links.extend(urls.into_iter().map(|url| Link {
+                    url,
+                    nofollow,
+                    requisite,
+                }));
+            }
+        }
+    }
+
+    links
+}
+
+/// Rewrites the links found by [`extract_links`]. `convert` gets each link
+/// as written (entities decoded) and returns its replacement, or `None` to
+/// leave it alone. Only the start tags holding a rewritten link and the
+/// rewritten `<style>` contents change, the rest of the document is kept
+/// byte for byte. `<base>`
+/// elements are dropped, the converted links being relative to the saved
+/// file.
+pub fn rewrite_links(html: &str, convert: &dyn Fn(&str) -> Option<String>) -> WgetResult<String> {
+    // a `<style>` may come in several chunks, its CSS is rewritten whole
+    let style = RefCell::new(String::new());
+
+    let mut handlers = vec![element!("base", |element| {
+        element.remove();
+        Ok(())
+    })];
+    for (selector, attr) in LINK_ATTRIBUTES {
+        if *attr == "textContent" {
+            handlers.push(text!(*selector, |chunk| {
+                let mut css_content = style.borrow_mut();
+                css_content.push_str(chunk.as_str());
+                if chunk.last_in_text_node() {
+                    let rewritten = css::rewrite_urls(&css_content, convert);
+                    chunk.replace(&rewritten, ContentType::Html);
+                    css_content.clear();
+                } else {
+                    chunk.remove();
+                }
+                Ok(())
+            }));
+        } else {
+            handlers.push(element!(*selector, move |element| {
+                rewrite_attribute(element, attr, convert);
+                Ok(())
+            }));
+        }
+    }
+
+    let rewritten = lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: handlers,
+            ..RewriteStrSettings::new()
+        },
+    )?;
+    Ok(rewritten)
+}
+
+fn rewrite_attribute(element: &mut Element, attr: &str, convert: &dyn Fn(&str) -> Option<String>) {
+    let name = match attr {
+        "xlink:href" if !element.has_attribute("xlink:href") => "href",
+        name => name,
+    };
+    let Some(value) = element.get_attribute(name) else {
+        return;
+    };
+    let convert = |link: &str| convert(&decode_entities(link));
+
+    let rewritten = match attr {
+        "style" => Some(css::rewrite_urls(&value, convert)),
+        "srcset" | "imagesrcset" => splice(&value, srcset_spans(&value), convert),
+        "content" if is_refresh(element.get_attribute("http-equiv").as_deref()) => {
+            splice(&value, refresh_span(&value).into_iter().collect(), convert)
+        }
+        "content" => None,
+        _ => convert(&value),
+    };
+
+    if let Some(rewritten) = rewritten.filter(|rewritten| *rewritten != value) {
+        // the name comes from the page, it can't be invalid
+        let _ = element.set_attribute(name, &rewritten);
+    }
+}
+
+/// Replaces the given parts of `value`, `None` if `convert` kept them all.
+fn splice(
+    value: &str,
+    spans: Vec<Range<usize>>,
+    convert: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    let mut output = String::with_capacity(value.len());
+    let mut last = 0;
+    let mut changed = false;
+
+    for span in spans {
+        if let Some(new) = convert(&value[span.clone()]) {
+            output.push_str(&value[last..span.start]);
+            output.push_str(&new);
+            last = span.end;
+            changed = true;
+        }
+    }
+
+    output.push_str(&value[last..]);
+    changed.then_some(output)
+}
+
+/// Attribute values are read as written, this decodes the entities found
+/// in URLs, named (`&amp;`) or numeric (`&#47;`, `&#x2F;`).
+fn decode_entities(value: &str) -> String {
+    if !value.contains('&') {
+        return value.to_string();
+    }
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('&') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest
+            .find(';')
+            .and_then(|end| Some((entity(&rest[1..end])?, end)));
+        match decoded {
+            Some((c, end)) => {
+                output.push(c);
+                rest = &rest[end + 1..];
+            }
+            // not an entity we know, keep the `&` as written
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// The character of an entity name such as `amp`, `#47` or `#x2F`.
+fn entity(name: &str) -> Option<char> {
+    match name {
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "amp" => Some('&'),
+        _ => {
+            let number = name.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// The `href` of the page's first `<base>` element, as written.
+pub fn base_href(html: &str) -> Option<String> {
+    let document = Html::parse_document(html);
+    let selector = Selector::parse("base[href]").unwrap();
+    document
+        .select(&selector)
+        .next()
+        .and_then(|element| element.value().attr("href"))
+        .map(|href| href.trim().to_string())
+}
+
+fn is_refresh(http_equiv: Option<&str>) -> bool {
+    http_equiv.is_some_and(|value| value.eq_ignore_ascii_case("refresh"))
+}
+
+/// Where the target is in a `<meta http-equiv="refresh">` content such as
+/// `5; url='next.html'`, `None` for a plain reload.
+fn refresh_span(content: &str) -> Option<Range<usize>> {
+    let skip_spaces =
+        |start: usize| start + content[start..].len() - content[start..].trim_start().len();
+
+    let mut start = skip_spaces(content.find([';', ','])? + 1);
+    if content
+        .get(start..start + 3)
+        .is_some_and(|key| key.eq_ignore_ascii_case("url"))
+    {
+        let after = content[start + 3..].trim_start();
+        start = skip_spaces(content.len() - after.strip_prefix('=')?.len());
+    }
+    let mut end = start + content[start..].trim_end().len();
+    if content[start..end].starts_with(['\'', '"']) {
+        start += 1;
+    }
+    if end > start && content[start..end].ends_with(['\'', '"']) {
+        end -= 1;
+    }
+    (start < end).then_some(start..end)
+}
+
+/// Where the candidate URLs are in a `srcset`, as in
+/// `small.jpg 480w, large.jpg 1080w`. A URL runs up to the next whitespace,
+/// a trailing comma ending the candidate; otherwise its descriptors run up
+/// to the next comma.
+fn srcset_spans(srcset: &str) -> Vec<Range<usize>> {
+    let mut spans = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let rest = &srcset[pos..];
+        pos += rest.len()
+            - rest
+                .trim_start_matches(|c: char| c.is_whitespace() || c == ',')
+                .len();
+        let rest = &srcset[pos..];
+        if rest.is_empty() {
+            break;
+        }
+        let candidate = &rest[..rest.find(char::is_whitespace).unwrap_or(rest.len())];
+        let url_len = candidate.trim_end_matches(',').len();
+        spans.push(pos..pos + url_len);
+        pos += candidate.len();
+        if url_len == candidate.len() {
+            // skip the descriptors
+            let after = &srcset[pos..];
+            pos += after.find(',').map_or(after.len(), |index| index + 1);
+        }
+    }
+
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(text: &str, spans: Vec<Range<usize>>) -> Vec<&str> {
+        spans.into_iter().map(|span| &text[span]).collect()
+    }
+
+    #[test]
+    fn srcset_candidates() {
+        let srcset = "small.jpg 480w, large.jpg 1080w";
+        assert_eq!(
+            spans(srcset, srcset_spans(srcset)),
+            ["small.jpg", "large.jpg"]
+        );
+        let srcset = "a.jpg, b.jpg 2x , c.jpg,";
+        assert_eq!(
+            spans(srcset, srcset_spans(srcset)),
+            ["a.jpg", "b.jpg", "c.jpg"]
+        );
+        // commas inside a URL don't end it, only whitespace does
+        let srcset = "a.jpg,b.jpg 2x";
+        assert_eq!(spans(srcset, srcset_spans(srcset)), ["a.jpg,b.jpg"]);
+        let srcset = "img.php?w=1,2 1x, other.png 2x";
+        assert_eq!(
+            spans(srcset, srcset_spans(srcset)),
+            ["img.php?w=1,2", "other.png"]
+        );
+        assert!(srcset_spans("  , ").is_empty());
+    }
+
+    #[test]
+    fn refresh_targets() {
+        let target = |content: &'static str| refresh_span(content).map(|span| &content[span]);
+        assert_eq!(target("5; url=next.html"), Some("next.html"));
+        assert_eq!(target("0;URL = 'next.html' "), Some("next.html"));
+        assert_eq!(target("0, \"next.html\""), Some("next.html"));
+        assert_eq!(target("3;next.html"), Some("next.html"));
+        assert_eq!(target("30"), None);
+        assert_eq!(target("0; url="), None);
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(decode_entities("a?b=1&amp;c=2"), "a?b=1&c=2");
+        assert_eq!(decode_entities("&#47;a&#x2F;b&#X2f;c"), "/a/b/c");
+        assert_eq!(decode_entities("&#39;q&quot;"), "'q\"");
+        // decoded once, `&amp;lt;` is the text `&lt;`
+        assert_eq!(decode_entities("&amp;lt;"), "&lt;");
+        assert_eq!(decode_entities("a&b; c&d"), "a&b; c&d");
+        assert_eq!(decode_entities("&#xD800;"), "&#xD800;");
+    }
+
+    fn to_page(link: &str) -> Option<String> {
+        (link == "/a").then(|| "a.html".to_string())
+    }
+
+    #[test]
+    fn text_is_not_rewritten() {
+        let html = "<p>/a</p><a href=\"/a\">/a</a><script>var u = \"/a\";</script>";
+        assert_eq!(
+            rewrite_links(html, &to_page).unwrap(),
+            "<p>/a</p><a href=\"a.html\">/a</a><script>var u = \"/a\";</script>"
+        );
+    }
+
+    #[test]
+    fn links_are_rewritten_whole() {
+        let html = "<a href=\"/a/b\">b</a><img srcset=\"/a/b 1x, /a 2x\">";
+        assert_eq!(
+            rewrite_links(html, &to_page).unwrap(),
+            "<a href=\"/a/b\">b</a><img srcset=\"/a/b 1x, a.html 2x\">"
+        );
+    }
+
+    #[test]
+    fn numeric_entities_are_decoded() {
+        let html = "<a href=\"&#47;a\">a</a><a href=\"&#x2F;a\">a</a>";
+        assert_eq!(
+            rewrite_links(html, &to_page).unwrap(),
+            "<a href=\"a.html\">a</a><a href=\"a.html\">a</a>"
+        );
+    }
+
+    #[test]
+    fn rest_of_the_document_is_kept() {
+        let html = "<!DOCTYPE html>\n<HTML><Head><base href=\"/x/\"><title>T &amp; t</title>\n\
+                    <style>\n  p { background: url( '/a' ) }\n</style></Head>\n\
+                    <body CLASS=x><!-- <a href=\"/a\"> -->\n<IMG SRC='/b' alt=a>&nbsp;<br/>\n\
+                    <img src=/a alt='/a'></body></HTML>\n";
+        let expected = "<!DOCTYPE html>\n<HTML><Head><title>T &amp; t</title>\n\
+                    <style>\n  p { background: url( 'a.html' ) }\n</style></Head>\n\
+                    <body CLASS=x><!-- <a href=\"/a\"> -->\n<IMG SRC='/b' alt=a>&nbsp;<br/>\n\
+                    <img src=\"a.html\" alt='/a'></body></HTML>\n";
+        assert_eq!(rewrite_links(html, &to_page).unwrap(), expected);
+    }
+}
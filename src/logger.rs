@@ -0,0 +1,195 @@
+use crate::{utils, Args, WgetResult};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// How much output is produced, from `-q` up to `-d`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    NoVerbose,
+    #[default]
+    Verbose,
+    Debug,
+}
+
+/// Single output channel shared by the downloader, the spawned `-i` tasks
+/// and the mirror. Writes to the terminal unless a log file was requested
+/// with `-o`, `-a` or `-B`.
+#[derive(Clone, Default)]
+pub struct Logger {
+    file: Option<Arc<Mutex<File>>>,
+    path: Option<PathBuf>,
+    verbosity: Verbosity,
+}
+
+impl Logger {
+    pub fn stdout() -> Self {
+        Self::default()
+    }
+
+    pub fn to_file(path: impl Into<PathBuf>, append: bool) -> WgetResult<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(&path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        Ok(Self {
+            file: Some(Arc::new(Mutex::new(file))),
+            path: Some(path),
+            verbosity: Verbosity::default(),
+        })
+    }
+
+    pub fn with_verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Picks the log destination from the command line: `-o` truncates,
+    /// `-a` appends, and `-B` alone falls back to the next free `wget-log`.
+    pub fn from_args(args: &Args) -> WgetResult<Self> {
+        let logger = if let Some(path) = &args.output_file {
+            Self::to_file(path, false)?
+        } else if let Some(path) = &args.append_output {
+            Self::to_file(path, true)?
+        } else if args.background {
+            Self::to_file(next_free_log_name(), false)?
+        } else {
+            Self::stdout()
+        };
+        Ok(logger.with_verbosity(args.verbosity()))
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    /// Progress bars only make sense on a terminal in verbose mode.
+    pub fn shows_progress(&self) -> bool {
+        !self.is_file() && self.verbosity >= Verbosity::Verbose
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Verbose output, the default level.
+    pub fn log(&self, message: &str) {
+        if self.verbosity < Verbosity::Verbose {
+            return;
+        }
+        match &self.file {
+            Some(file) => write_to(file, message),
+            None => {
+                print!("{}", message);
+                let _ = std::io::stdout().flush();
+            }
+        }
+    }
+
+    pub fn logln(&self, message: &str) {
+        if self.verbosity < Verbosity::Verbose {
+            return;
+        }
+        self.writeln(message);
+    }
+
+    /// One-line summaries kept by `-nv`.
+    pub fn notice(&self, message: &str) {
+        if self.verbosity < Verbosity::NoVerbose {
+            return;
+        }
+        self.writeln(message);
+    }
+
+    pub fn debug(&self, message: &str) {
+        if self.verbosity < Verbosity::Debug {
+            return;
+        }
+        self.writeln(&format!("[debug] {}", message));
+    }
+
+    /// Dumps the request line and headers we are about to send.
+    pub fn debug_request(&self, request: &reqwest::Request) {
+        if self.verbosity < Verbosity::Debug {
+            return;
+        }
+        self.debug(&format!(
+            "---request begin---\n{} {}",
+            request.method(),
+            request.url()
+        ));
+        for (name, value) in request.headers() {
+            self.writeln(&format!(
+                "{}: {}",
+                name,
+                value.to_str().unwrap_or("<binary>")
+            ));
+        }
+        self.writeln("---request end---");
+    }
+
+    pub fn debug_response(&self, response: &reqwest::Response) {
+        if self.verbosity < Verbosity::Debug {
+            return;
+        }
+        self.debug(&format!(
+            "---response begin---\n{:?} {}",
+            response.version(),
+            response.status()
+        ));
+        for (name, value) in response.headers() {
+            self.writeln(&format!(
+                "{}: {}",
+                name,
+                value.to_str().unwrap_or("<binary>")
+            ));
+        }
+        self.writeln("---response end---");
+    }
+
+    /// Errors are only silenced by `-q`.
+    pub fn error(&self, message: &str) {
+        if self.verbosity < Verbosity::NoVerbose {
+            return;
+        }
+        match &self.file {
+            Some(file) => write_to(file, &format!("{}\n", message)),
+            None => eprintln!("{}", message),
+        }
+    }
+
+    fn writeln(&self, message: &str) {
+        match &self.file {
+            Some(file) => write_to(file, &format!("{}\n", message)),
+            None => println!("{}", message),
+        }
+    }
+}
+
+fn write_to(file: &Mutex<File>, message: &str) {
+    let mut file = utils::lock(file);
+    let _ = file.write_all(message.as_bytes());
+    let _ = file.flush();
+}
+
+/// `wget-log`, then `wget-log.1`, `wget-log.2`... like GNU wget.
+pub fn next_free_log_name() -> String {
+    let mut name = "wget-log".to_string();
+    let mut index = 1;
+    while Path::new(&name).exists() {
+        name = format!("wget-log.{}", index);
+        index += 1;
+    }
+    name
+}
@@ -1,14 +1,42 @@
-use clap::Parser;
-use wget::{Args, Downloader, WgetResult};
+use wget::{background, config, Downloader, Logger, WgetResult};
 
-#[tokio::main]
-async fn main() -> WgetResult<()> {
-    let args = Args::parse();
+fn main() -> WgetResult<()> {
+    let args = match config::load_args() {
+        Ok(args) => args,
+        Err(err) => exit_with_error(&err.to_string()),
+    };
     if let Err(err) = args.validate() {
-        eprintln!("{}", err);
+        exit_with_error(&err);
+    }
+
+    let logger = match Logger::from_args(&args) {
+        Ok(logger) => logger,
+        Err(err) => exit_with_error(&err.to_string()),
+    };
+    if args.background {
+        // only announce the log file when we picked its name ourselves
+        let explicit_log = args.output_file.is_some() || args.append_output.is_some();
+        background::detach(if explicit_log { None } else { logger.path() })?;
+    }
+
+    // the runtime is built after detaching, fork() and worker threads don't mix
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(async {
+        let mut downloader = Downloader::new(args, logger.clone())?;
+        downloader.download_all().await
+    });
+
+    // stderr is /dev/null once in the background, errors go to the log too
+    if let Err(err) = result {
+        logger.error(&format!("wget: {}", err));
         std::process::exit(1);
     }
-    let mut downloader = Downloader::new(args).await?;
-    downloader.download_all().await?;
     Ok(())
 }
+
+/// Errors carry no `wget: ` prefix of their own, it is added here and when
+/// they are logged.
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("wget: {}", message);
+    std::process::exit(1);
+}
@@ -1,213 +1,1121 @@
-use crate::WgetResult;
-use reqwest::Client;
-use scraper::{Html, Selector};
-use std::collections::{HashSet, VecDeque};
+use crate::css;
+use crate::filename::{self, DirectoryLayout, FileNameRules};
+use crate::filter::{self, UrlFilter};
+use crate::limiter::RateLimiter;
+use crate::links::{base_href, extract_links, rewrite_links, Link};
+use crate::robots::{self, MetaRobots, RobotsRules};
+use crate::{utils, Args, Logger, Verbosity, WgetResult};
+use futures_util::{stream, StreamExt};
+use indicatif::MultiProgress;
+use reqwest::header::{IF_MODIFIED_SINCE, LOCATION};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::fs::{self, File};
 use tokio::io::AsyncWriteExt;
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
 use url::Url;
 
+/// How often the crawl state is saved while a level is being crawled.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Crawls a site breadth-first, one depth level at a time. The URLs of a
+/// level are fetched by up to `max_threads` concurrent workers, and the links
+/// they find are merged back in frontier order rather than completion order,
+/// so a given site always produces the same crawl.
 pub struct WebsiteMirror {
+    /// follows redirects, for robots.txt
     client: Client,
+    /// returns redirects, pages check each hop against the crawl's scope
+    page_client: Client,
+    /// `-P`, the layout below it is up to `layout`
     output_dir: PathBuf,
-    visited_urls: HashSet<String>,
-    rejected_extensions: HashSet<String>,
+    layout: DirectoryLayout,
+    start_host: String,
+    /// hosts the start URL redirected to, crawled like the start host
+    start_aliases: Mutex<HashSet<String>>,
+    /// `-H`: follow links to other hosts
+    span_hosts: bool,
+    /// `-D`: with `-H`, hosts must be in one of these domains
+    domains: HashSet<String>,
+    /// `--exclude-domains`: hosts never followed
+    excluded_domains: HashSet<String>,
+    /// every URL ever queued, checked before queueing so the frontier
+    /// never holds duplicates. URLs are compared in their normalized form.
+    seen_urls: HashSet<String>,
+    /// `--canonicalize-urls`, see [`normalize_url`]
+    canonicalize_urls: bool,
+    /// redirect targets by the URL that redirected to them, so converted
+    /// links to the old URL reach the saved file
+    redirects: Mutex<HashMap<String, String>>,
+    /// every redirect target fetched, each is saved once whatever the
+    /// number of URLs leading to it
+    redirect_targets: Mutex<HashSet<String>>,
+    filter: UrlFilter,
     excluded_paths: HashSet<String>,
+    included_paths: HashSet<String>,
+    /// `--no-parent`: directory of the start URL, nothing above it is crawled
+    no_parent_dir: Option<String>,
     convert_links: bool,
-    queue: VecDeque<String>,
+    /// `-p`: fetch what the pages of the last level need to render
+    page_requisites: bool,
+    /// `--restrict-file-names`
+    file_names: FileNameRules,
+    /// `-E`: name files after their `Content-Type` rather than guessing
+    adjust_extension: bool,
+    /// `-K`: keep the original of converted files as `<file>.orig`
+    backup_converted: bool,
+    /// `--content-on-error`: save error pages instead of skipping them
+    content_on_error: bool,
+    /// `--rate-limit`, for all the downloads together
+    rate_limiter: Option<RateLimiter>,
+    /// `--max-file-size`
+    max_file_size: Option<u64>,
+    /// progress bars of the files being streamed to disk
+    progress: Option<MultiProgress>,
+    /// `None` means no limit (`-l inf`, `--mirror`)
+    max_depth: Option<u32>,
+    timestamping: bool,
+    /// URLs of the level being crawled
+    frontier: Vec<String>,
+    /// level of `frontier`, not 0 when resuming
+    depth: u32,
+    /// links found by the part of the level crawled before an interruption
+    found: Vec<String>,
+    /// the start URL, normalized, to check a state file belongs to this crawl
+    start_url: String,
+    /// crawl state saved in the output directory for `--resume-crawl`
+    state_path: PathBuf,
+    /// `--resume-crawl`
+    resume: bool,
+    /// files being written, removed on interruption rather than left
+    /// truncated
+    in_progress: Mutex<HashSet<PathBuf>>,
+    max_threads: usize,
+    max_threads_per_host: usize,
+    host_limits: Mutex<HashMap<String, Arc<Semaphore>>>,
+    /// honor robots.txt and robots meta tags (`-e robots=off` to disable)
+    robots: bool,
+    /// robots.txt rules per origin, fetched on first use
+    robots_cache: Mutex<HashMap<String, Arc<OnceCell<Arc<RobotsRules>>>>>,
+    /// earliest start of the next request per host, for `Crawl-delay`
+    next_request: Mutex<HashMap<String, Instant>>,
+    /// every file on disk at the end of the crawl, by URL, for the
+    /// `--convert-links` pass
+    saved_files: Mutex<HashMap<String, SavedFile>>,
+    /// which file each name was given to, see [`Self::path_key`], to tell
+    /// apart files saved to the same name
+    claimed_paths: Mutex<HashMap<PathBuf, String>>,
+    /// URLs that could not be downloaded and why, for the final report
+    failures: Mutex<Vec<(String, String)>>,
+    logger: Logger,
+}
+
+/// A file of the mirror, downloaded by this run or found up to date.
+#[derive(Clone, Serialize, Deserialize)]
+struct SavedFile {
+    /// the URL as fetched, links of the file are relative to it
+    url: String,
+    path: PathBuf,
+    kind: FileKind,
+    /// false when the server answered 304 and the file was kept
+    downloaded: bool,
+}
+
+/// A response body, read whole when its links are needed and streamed to
+/// disk otherwise.
+enum Body {
+    Loaded(Vec<u8>),
+    Stream(reqwest::Response),
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum FileKind {
+    Html,
+    Css,
+    Other,
 }
 
 impl WebsiteMirror {
-    pub fn new(
-        url: String,
-        rejected_extensions: HashSet<String>,
-        excluded_paths: HashSet<String>,
-        convert_links: bool,
-    ) -> WgetResult<Self> {
+    pub fn new(url: String, args: &Args, logger: Logger) -> WgetResult<Self> {
         let base_url = Url::parse(&url)?;
+        let url = without_fragment(&base_url);
         let domain = base_url
             .host_str()
             .ok_or("Invalid URL: no host")?
             .to_string();
-        let output_dir = PathBuf::from(&domain);
+        let output_dir = args.path.as_ref().map(PathBuf::from).unwrap_or_default();
+        let state_path = output_dir.join(state_file_name(&url));
 
         Ok(Self {
-            client: Client::new(),
+            client: utils::build_client(&logger)?,
+            page_client: utils::build_manual_redirect_client()?,
             output_dir,
-            visited_urls: HashSet::new(),
-            rejected_extensions,
-            excluded_paths,
-            convert_links,
-            queue: VecDeque::from([url]),
+            layout: DirectoryLayout::from_args(args),
+            start_host: domain,
+            start_aliases: Mutex::new(HashSet::new()),
+            span_hosts: args.span_hosts,
+            domains: args.get_domains(),
+            excluded_domains: args.get_excluded_domains(),
+            seen_urls: HashSet::from([normalize_url(&base_url, args.canonicalize_urls)]),
+            canonicalize_urls: args.canonicalize_urls,
+            redirects: Mutex::new(HashMap::new()),
+            redirect_targets: Mutex::new(HashSet::new()),
+            filter: UrlFilter::from_args(args)?,
+            excluded_paths: dir_list(args.get_excluded_paths(), args.ignore_case),
+            included_paths: dir_list(args.get_included_paths(), args.ignore_case),
+            no_parent_dir: args
+                .no_parent
+                .then(|| base_url.path()[..=base_url.path().rfind('/').unwrap_or(0)].to_string()),
+            convert_links: args.convert_links,
+            page_requisites: args.page_requisites,
+            file_names: FileNameRules::from_args(args)?,
+            adjust_extension: args.adjust_extension,
+            backup_converted: args.backup_converted,
+            content_on_error: args.content_on_error,
+            rate_limiter: args.rate_limit_bytes()?.map(RateLimiter::new),
+            max_file_size: args.max_file_size_bytes()?,
+            progress: logger.shows_progress().then(MultiProgress::new),
+            max_depth: args.max_depth()?,
+            timestamping: args.timestamping || args.mirror,
+            frontier: vec![url.clone()],
+            depth: 0,
+            found: Vec::new(),
+            state_path,
+            start_url: url,
+            resume: args.resume_crawl,
+            in_progress: Mutex::new(HashSet::new()),
+            max_threads: args.max_threads.max(1),
+            max_threads_per_host: args.max_threads_per_host.max(1),
+            host_limits: Mutex::new(HashMap::new()),
+            robots: args.robots,
+            robots_cache: Mutex::new(HashMap::new()),
+            next_request: Mutex::new(HashMap::new()),
+            saved_files: Mutex::new(HashMap::new()),
+            claimed_paths: Mutex::new(HashMap::new()),
+            failures: Mutex::new(Vec::new()),
+            logger,
         })
     }
 
     pub async fn start(&mut self) -> WgetResult<()> {
         // create the output directory
-        fs::create_dir_all(&self.output_dir).await?;
+        if !self.output_dir.as_os_str().is_empty() {
+            fs::create_dir_all(&self.output_dir).await?;
+        }
 
-        while let Some(url) = self.queue.pop_front() {
-            if self.visited_urls.contains(&url) {
-                continue;
+        if self.resume {
+            self.load_state().await?;
+        }
+
+        // Ctrl-C saves the crawl state before stopping
+        let mut interrupt = Box::pin(tokio::signal::ctrl_c());
+        let mut depth = self.depth;
+        let mut found = std::mem::take(&mut self.found);
+        while !self.frontier.is_empty() || !found.is_empty() {
+            let level = std::mem::take(&mut self.frontier);
+            let this = &*self;
+
+            // file names are handed out in frontier order too
+            for url in &level {
+                if let (Ok(key), Ok(path)) = (this.path_key(url, ""), this.local_path(url)) {
+                    this.claim_path(&key, path);
+                }
             }
 
-            if let Err(e) = self.process_url(&url).await {
-                eprintln!("Error processing {}: {}", url, e);
+            let mut crawl = stream::iter(level.iter().enumerate())
+                .map(|(index, url)| async move {
+                    let links = match this.process_url(url, depth).await {
+                        Ok(links) => links,
+                        Err(e) => {
+                            this.logger
+                                .error(&format!("Error processing {}: {}", url, e));
+                            this.record_failure(url, &e.to_string());
+                            Vec::new()
+                        }
+                    };
+                    (index, links)
+                })
+                .buffer_unordered(this.max_threads);
+
+            let mut results: Vec<(usize, Vec<String>)> = Vec::new();
+            let mut last_save = Instant::now();
+            loop {
+                tokio::select! {
+                    result = crawl.next() => match result {
+                        Some(result) => results.push(result),
+                        None => break,
+                    },
+                    _ = &mut interrupt => {
+                        // stop the downloads before cleaning up after them
+                        drop(crawl);
+                        this.save_interrupted(depth, &level, &found, &results).await?;
+                        return Err("interrupted".into());
+                    }
+                }
+                if last_save.elapsed() >= STATE_SAVE_INTERVAL {
+                    this.save_state(depth, &level, &found, &results).await?;
+                    last_save = Instant::now();
+                }
+            }
+            drop(crawl);
+
+            // merge in frontier order so the next level doesn't depend on timing
+            results.sort_by_key(|(index, _)| *index);
+            // pages reached through a redirect are not fetched again
+            self.seen_urls
+                .extend(utils::lock(&self.redirect_targets).iter().cloned());
+            let links = found
+                .drain(..)
+                .chain(results.into_iter().flat_map(|(_, links)| links));
+            for link in links {
+                if self.seen_urls.insert(self.url_key(&link)) {
+                    self.frontier.push(link);
+                }
+            }
+
+            depth += 1;
+            self.save_state(depth, &self.frontier, &[], &[]).await?;
+        }
+
+        // links are converted once we know which files were saved
+        if self.convert_links {
+            tokio::select! {
+                result = self.convert_saved_files() => result?,
+                // the state is kept, resuming only converts again
+                _ = &mut interrupt => return Err("interrupted".into()),
             }
+        }
+
+        self.report();
 
-            self.visited_urls.insert(url);
+        // nothing left to resume
+        match fs::remove_file(&self.state_path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
         }
+    }
+
+    /// Writes the crawl state: the URLs of `level` without a result yet,
+    /// the links found by the others, and everything queued and saved so
+    /// far. The file is replaced atomically, a crash while saving keeps
+    /// the previous state.
+    async fn save_state(
+        &self,
+        depth: u32,
+        level: &[String],
+        found: &[String],
+        results: &[(usize, Vec<String>)],
+    ) -> WgetResult<()> {
+        let done: HashSet<usize> = results.iter().map(|(index, _)| *index).collect();
+        let mut results: Vec<&(usize, Vec<String>)> = results.iter().collect();
+        results.sort_by_key(|(index, _)| *index);
 
+        let state = MirrorState {
+            start_url: self.start_url.clone(),
+            depth,
+            pending: level
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !done.contains(index))
+                .map(|(_, url)| url.clone())
+                .collect(),
+            found: found
+                .iter()
+                .chain(results.iter().flat_map(|(_, links)| links))
+                .cloned()
+                .collect(),
+            seen_urls: self.seen_urls.iter().cloned().collect(),
+            saved_files: utils::lock(&self.saved_files).clone(),
+            claimed_paths: utils::lock(&self.claimed_paths)
+                .iter()
+                .map(|(path, url)| (path.clone(), url.clone()))
+                .collect(),
+            redirects: utils::lock(&self.redirects).clone(),
+            redirect_targets: utils::lock(&self.redirect_targets)
+                .iter()
+                .cloned()
+                .collect(),
+            start_aliases: utils::lock(&self.start_aliases).iter().cloned().collect(),
+            failures: utils::lock(&self.failures).clone(),
+        };
+
+        let temp_path = append_to_path(&self.state_path, ".tmp");
+        fs::write(&temp_path, serde_json::to_vec(&state)?).await?;
+        fs::rename(&temp_path, &self.state_path).await?;
+        self.logger.debug(&format!(
+            "crawl state saved to {}",
+            self.state_path.display()
+        ));
         Ok(())
     }
 
-    async fn process_url(&mut self, url: &str) -> WgetResult<()> {
-        println!("Processing: {}", url);
+    /// Ctrl-C: files cut off mid-download are removed, they will be fetched
+    /// again on resume, and the state is saved.
+    async fn save_interrupted(
+        &self,
+        depth: u32,
+        level: &[String],
+        found: &[String],
+        results: &[(usize, Vec<String>)],
+    ) -> WgetResult<()> {
+        let partial: Vec<PathBuf> = utils::lock(&self.in_progress).drain().collect();
+        for path in partial {
+            let _ = fs::remove_file(&path).await;
+        }
+        self.save_state(depth, level, found, results).await?;
+        self.logger.error(&format!(
+            "Interrupted, crawl state saved to {}. Run again with --resume-crawl to continue.",
+            self.state_path.display()
+        ));
+        Ok(())
+    }
+
+    /// `--resume-crawl`: picks up the crawl saved by an earlier run, if any.
+    async fn load_state(&mut self) -> WgetResult<()> {
+        let content = match fs::read(&self.state_path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.logger.logln(&format!(
+                    "No crawl state in {}, starting from the beginning.",
+                    self.state_path.display()
+                ));
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let state: MirrorState = serde_json::from_slice(&content)
+            .map_err(|e| format!("{}: invalid crawl state: {}", self.state_path.display(), e))?;
+        if state.start_url != self.start_url {
+            return Err(format!(
+                "{}: crawl state of {}, not {}",
+                self.state_path.display(),
+                state.start_url,
+                self.start_url
+            )
+            .into());
+        }
+
+        self.logger.logln(&format!(
+            "Resuming crawl at level {}: {} URLs left in the level, {} files saved.",
+            state.depth,
+            state.pending.len(),
+            state.saved_files.len()
+        ));
+        self.depth = state.depth;
+        self.frontier = state.pending;
+        self.found = state.found;
+        self.seen_urls = state.seen_urls.into_iter().collect();
+        *utils::lock(&self.saved_files) = state.saved_files;
+        *utils::lock(&self.claimed_paths) = state.claimed_paths.into_iter().collect();
+        *utils::lock(&self.redirects) = state.redirects;
+        *utils::lock(&self.redirect_targets) = state.redirect_targets.into_iter().collect();
+        *utils::lock(&self.start_aliases) = state.start_aliases.into_iter().collect();
+        *utils::lock(&self.failures) = state.failures;
+        Ok(())
+    }
+
+    /// Summary printed at the end of the crawl, with every URL that failed.
+    fn report(&self) {
+        let downloaded = utils::lock(&self.saved_files)
+            .values()
+            .filter(|file| file.downloaded)
+            .count();
+        self.logger
+            .notice(&format!("Downloaded: {} files", downloaded));
+
+        let mut failures = utils::lock(&self.failures);
+        if failures.is_empty() {
+            return;
+        }
+        failures.sort();
+        self.logger
+            .notice(&format!("{} URLs could not be downloaded:", failures.len()));
+        for (url, reason) in failures.iter() {
+            self.logger.notice(&format!("  {} ({})", url, reason));
+        }
+    }
+
+    fn record_failure(&self, url: &str, reason: &str) {
+        utils::lock(&self.failures).push((url.to_string(), reason.to_string()));
+    }
+
+    /// Rewrites the links of every saved page and stylesheet: links to saved
+    /// files point to the local copy, all the others become absolute. With
+    /// `-K` the original is kept next to it as `<file>.orig`.
+    async fn convert_saved_files(&self) -> WgetResult<()> {
+        let start = std::time::Instant::now();
+        let mut documents: Vec<(String, PathBuf, FileKind, bool)> = utils::lock(&self.saved_files)
+            .values()
+            .filter(|file| file.kind != FileKind::Other)
+            .map(|file| {
+                (
+                    file.url.clone(),
+                    file.path.clone(),
+                    file.kind,
+                    file.downloaded,
+                )
+            })
+            .collect();
+        documents.sort_by(|a, b| a.0.cmp(&b.0));
+        // a file reached by several URLs (`/` and `/index.html`) is converted
+        // once, a second pass would no longer find its links
+        let mut converted_paths = HashSet::new();
+        documents.retain(|(_, path, _, _)| converted_paths.insert(path.clone()));
+
+        for (url, path, kind, downloaded) in &documents {
+            self.logger
+                .logln(&format!("Converting links in {}... ", path.display()));
+            let orig = orig_path(path);
+
+            // a file kept from a previous -K run is already converted, its
+            // original is in the .orig
+            let content = if !downloaded && self.backup_converted && orig.exists() {
+                fs::read_to_string(&orig).await?
+            } else {
+                let content = fs::read_to_string(path).await?;
+                if self.backup_converted {
+                    fs::copy(path, &orig).await?;
+                    // -N compares the server's date against the .orig
+                    if let Some(modified) = utils::local_modified_time(path).await {
+                        std::fs::File::options()
+                            .write(true)
+                            .open(&orig)?
+                            .set_modified(modified)?;
+                    }
+                }
+                content
+            };
+
+            let page_url = Url::parse(url)?;
+            let converted = match kind {
+                FileKind::Html => {
+                    // relative links are resolved against `<base href>`
+                    let base_url = base_href(&content)
+                        .and_then(|href| page_url.join(&href).ok())
+                        .unwrap_or_else(|| page_url.clone());
+                    rewrite_links(&content, &|link| {
+                        self.convert_link(&page_url, &base_url, link)
+                    })?
+                }
+                _ => css::rewrite_urls(&content, |reference| {
+                    self.convert_link(&page_url, &page_url, reference)
+                }),
+            };
+            fs::write(path, converted).await?;
+        }
+
+        self.logger.logln(&format!(
+            "Converted links in {} files in {:.2} seconds.",
+            documents.len(),
+            start.elapsed().as_secs_f64()
+        ));
+        Ok(())
+    }
+
+    fn record_saved_file(&self, url: &str, path: &Path, kind: FileKind, downloaded: bool) {
+        utils::lock(&self.saved_files).insert(
+            self.url_key(url),
+            SavedFile {
+                url: url.to_string(),
+                path: path.to_path_buf(),
+                kind,
+                downloaded,
+            },
+        );
+    }
+
+    /// Waits for a free connection slot on the URL's host.
+    async fn acquire_host_slot(&self, url: &str) -> WgetResult<OwnedSemaphorePermit> {
+        let host = Url::parse(url)?.host_str().unwrap_or_default().to_string();
+        let semaphore = utils::lock(&self.host_limits)
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_threads_per_host)))
+            .clone();
+        Ok(semaphore.acquire_owned().await?)
+    }
+
+    /// Returns the robots.txt rules of the URL's origin, fetching them once.
+    /// A missing or unreadable robots.txt allows everything. Only the
+    /// workers on the same origin wait for the download.
+    async fn robots_rules(&self, url: &Url) -> Arc<RobotsRules> {
+        let origin = url.origin().ascii_serialization();
+        let cell = utils::lock(&self.robots_cache)
+            .entry(origin.clone())
+            .or_default()
+            .clone();
+
+        cell.get_or_init(|| async {
+            let robots_url = format!("{}/robots.txt", origin);
+            self.logger.debug(&format!("Loading {}", robots_url));
+            let rules = match self.client.get(&robots_url).send().await {
+                Ok(response) if response.status().is_success() => response
+                    .text()
+                    .await
+                    .map(|content| RobotsRules::parse(&content))
+                    .unwrap_or_else(|_| RobotsRules::allow_all()),
+                _ => RobotsRules::allow_all(),
+            };
+            Arc::new(rules)
+        })
+        .await
+        .clone()
+    }
+
+    /// Spaces the requests to a host by its `Crawl-delay`. Each caller
+    /// reserves the next start time, then sleeps until it.
+    async fn wait_crawl_delay(&self, url: &Url, delay: f64) {
+        let host = url.host_str().unwrap_or_default().to_string();
+        let start = {
+            let mut next_request = utils::lock(&self.next_request);
+            let now = Instant::now();
+            let start = next_request.get(&host).map_or(now, |next| (*next).max(now));
+            next_request.insert(host, start + Duration::from_secs_f64(delay));
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+
+    /// Fetches and saves one URL, returning the links to crawl next.
+    async fn process_url(&self, url: &str, depth: u32) -> WgetResult<Vec<String>> {
+        self.logger.logln(&format!("Processing: {}", url));
+
+        // redirects are followed here rather than by the client, each hop
+        // must stay within the crawl
+        let mut url = url.to_string();
+        let mut parsed_url = Url::parse(&url)?;
+        let mut hops = 0;
+        let mut _slot = None;
+        let (response, url_path, mut full_path, original_path) = loop {
+            let robots_rules = if self.robots {
+                Some(self.robots_rules(&parsed_url).await)
+            } else {
+                None
+            };
+            if let Some(rules) = &robots_rules {
+                let path = match parsed_url.query() {
+                    Some(query) => format!("{}?{}", parsed_url.path(), query),
+                    None => parsed_url.path().to_string(),
+                };
+                if !rules.is_allowed(&path) {
+                    self.logger
+                        .logln(&format!("Rejecting {} (disallowed by robots.txt)", url));
+                    return Ok(Vec::new());
+                }
+            }
 
-        // check if the URL should be excluded
-        if self.should_exclude(url) {
-            return Ok(());
+            // release the previous host's slot before waiting for the next
+            _slot = None;
+            _slot = Some(self.acquire_host_slot(&url).await?);
+            if let Some(delay) = robots_rules.and_then(|rules| rules.crawl_delay) {
+                self.wait_crawl_delay(&parsed_url, delay).await;
+            }
+
+            let url_path = self.claim_path(&self.path_key(&url, "")?, self.local_path(&url)?);
+            // with -E the name depends on the Content-Type, look for the copy
+            // saved by a previous run
+            let full_path = if self.adjust_extension {
+                [".html", ".css"]
+                    .iter()
+                    .map(|extension| append_to_path(&url_path, extension))
+                    .find(|path| path.exists())
+                    .unwrap_or_else(|| url_path.clone())
+            } else {
+                url_path.clone()
+            };
+
+            // with -K the converted file has the date of the conversion, the
+            // .orig keeps the server's
+            let original_path = if self.backup_converted && orig_path(&full_path).exists() {
+                orig_path(&full_path)
+            } else {
+                full_path.clone()
+            };
+
+            let mut request = self.page_client.get(&url);
+            if self.timestamping {
+                if let Some(modified) = utils::local_modified_time(&original_path).await {
+                    request = request.header(IF_MODIFIED_SINCE, utils::format_http_date(modified));
+                }
+            }
+            let request = request.build()?;
+            self.logger.debug_request(&request);
+            let response = self.page_client.execute(request).await?;
+            self.logger.debug_response(&response);
+
+            let Some(target) = redirect_target(&response, &parsed_url) else {
+                break (response, url_path, full_path, original_path);
+            };
+            hops += 1;
+            if hops > utils::MAX_REDIRECTS {
+                return Err("too many redirects".into());
+            }
+            self.logger
+                .logln(&format!("Location: {} [following]", target));
+
+            // the start URL may move to another host (http to https,
+            // www...), the crawl moves with it
+            if depth == 0 {
+                if let Some(host) = target.host_str() {
+                    utils::lock(&self.start_aliases).insert(host.to_string());
+                }
+            } else if !self.redirect_allowed(&target) {
+                self.logger
+                    .logln(&format!("Redirection to {} rejected.", target));
+                return Ok(Vec::new());
+            }
+
+            let target_key = normalize_url(&target, self.canonicalize_urls);
+            utils::lock(&self.redirects).insert(self.url_key(&url), target_key.clone());
+            // the target is fetched only once, by whoever gets it first
+            let first = utils::lock(&self.redirect_targets).insert(target_key.clone());
+            if !first || self.seen_urls.contains(&target_key) {
+                return Ok(Vec::new());
+            }
+            url = without_fragment(&target);
+            parsed_url = Url::parse(&url)?;
+        };
+        let url = url.as_str();
+
+        let status = response.status();
+        if status == StatusCode::NOT_MODIFIED {
+            self.logger.logln(&format!(
+                "File \"{}\" not modified on server, omitting download.",
+                full_path.display()
+            ));
+            // the local copy of a page still has links to follow
+            if is_html_file(&full_path) {
+                self.record_saved_file(url, &full_path, FileKind::Html, false);
+                let html_content = fs::read_to_string(&original_path).await?;
+                if self.robots && robots::meta_directives(&html_content).nofollow {
+                    return Ok(Vec::new());
+                }
+                return self.process_html(&html_content, url, depth);
+            }
+            if is_css_file(&full_path) {
+                self.record_saved_file(url, &full_path, FileKind::Css, false);
+                let css_content = fs::read_to_string(&original_path).await?;
+                return self.process_css(&css_content, url, depth);
+            }
+            self.record_saved_file(url, &full_path, FileKind::Other, false);
+            return Ok(Vec::new());
+        }
+
+        // error pages are not content, unless --content-on-error
+        let error_page = !status.is_success();
+        if error_page {
+            self.logger.error(&format!(
+                "{} ERROR {}: {}.",
+                url,
+                status.as_u16(),
+                status.canonical_reason().unwrap_or("")
+            ));
+            self.record_failure(url, &status.to_string());
+            if !self.content_on_error {
+                return Ok(Vec::new());
+            }
         }
 
-        let response = self.client.get(url).send().await?;
         let content_type = response
             .headers()
             .get("content-type")
             .and_then(|v| v.to_str().ok())
-            .unwrap_or("");
+            .unwrap_or("")
+            .to_string();
+        let last_modified = utils::last_modified(&response);
 
-        // get the relative path for backup
-        let relative_path = self.get_relative_path(url)?;
+        let mut added_extension = "";
+        if self.adjust_extension {
+            let name = url_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            added_extension = utils::adjusted_extension(name, &content_type).unwrap_or("");
+            full_path = append_to_path(&url_path, added_extension);
+        }
 
-        let full_path = self.output_dir.join(&relative_path);
+        // pages rejected by -A/-R were only fetched for their links
+        let wanted = self.filter.is_wanted(&parsed_url);
+
+        if let (Some(max), Some(size)) = (self.max_file_size, response.content_length()) {
+            if size > max {
+                self.logger.notice(&format!(
+                    "Skipping {} ({} is over --max-file-size)",
+                    url,
+                    utils::format_size(size)
+                ));
+                return Ok(Vec::new());
+            }
+        }
+
+        let mut links = Vec::new();
+        let mut kind = FileKind::Other;
+        let content = if content_type.contains("text/html") {
+            let html_content = response.text().await?;
+            let meta = if self.robots {
+                robots::meta_directives(&html_content)
+            } else {
+                MetaRobots::default()
+            };
+            let page_links = self.process_html(&html_content, url, depth)?;
+            if !meta.nofollow {
+                links = page_links;
+            }
+            if meta.noindex {
+                self.logger
+                    .logln(&format!("Not saving {} (robots noindex)", url));
+                return Ok(links);
+            }
+            if !wanted {
+                self.logger.logln(&format!(
+                    "Removing {} since it should be rejected.",
+                    full_path.display()
+                ));
+                return Ok(links);
+            }
+            kind = FileKind::Html;
+            Body::Loaded(html_content.into_bytes())
+        } else if content_type.contains("text/css") {
+            let css_content = response.text().await?;
+            links = self.process_css(&css_content, url, depth)?;
+            kind = FileKind::Css;
+            Body::Loaded(css_content.into_bytes())
+        } else {
+            // anything else can be big, it goes straight to the file
+            Body::Stream(response)
+        };
+
+        if !wanted {
+            self.logger.logln(&format!("Rejecting {}", url));
+            return Ok(links);
+        }
+        // the links of an error page lead nowhere useful
+        if error_page {
+            links.clear();
+        }
+
+        let full_path = self.claim_path(&self.path_key(url, added_extension)?, full_path);
 
         // create parent directories if necessary
         if let Some(parent) = full_path.parent() {
             fs::create_dir_all(parent).await?;
         }
 
-        if content_type.contains("text/html") {
-            let html_content = response.text().await?;
-            let processed_html = self.process_html(&html_content, url)?;
-            let mut file = File::create(&full_path).await?;
-            file.write_all(processed_html.as_bytes()).await?;
-        } else if content_type.contains("text/css")
-            || content_type.contains("application/javascript")
-        {
-            let content = response.bytes().await?;
-            let mut file = File::create(&full_path).await?;
-            file.write_all(&content).await?;
-        } else {
-            // download the file directly
-            let content = response.bytes().await?;
-            let mut file = File::create(&full_path).await?;
-            file.write_all(&content).await?;
+        utils::lock(&self.in_progress).insert(full_path.clone());
+        let mut file = File::create(&full_path).await?;
+        let saved = match content {
+            Body::Loaded(content) => {
+                if let Some(limiter) = &self.rate_limiter {
+                    limiter.consume(content.len() as u64).await;
+                }
+                file.write_all(&content).await?;
+                Ok(true)
+            }
+            Body::Stream(response) => {
+                self.stream_to_file(url, response, &mut file, &full_path)
+                    .await
+            }
+        };
+        // no partial files left behind
+        if !matches!(saved, Ok(true)) {
+            drop(file);
+            fs::remove_file(&full_path).await?;
+            utils::lock(&self.in_progress).remove(&full_path);
+            return saved.map(|_| links);
         }
 
-        Ok(())
-    }
+        // keep the server's date so the next -N run can compare against it
+        if self.timestamping {
+            if let Some(modified) = last_modified {
+                file.into_std().await.set_modified(modified)?;
+            }
+        }
+        utils::lock(&self.in_progress).remove(&full_path);
 
-    fn process_html(&mut self, html: &str, base_url: &str) -> WgetResult<String> {
-        let document = Html::parse_document(html);
-        let base_url = Url::parse(base_url)?;
+        self.record_saved_file(url, &full_path, kind, true);
 
-        // Regex pour trouver les URLs dans les background-images
-        let bg_regex = regex::Regex::new(r#"url\(['"]?([^'")\s]+)['"]?\)"#).unwrap();
-
-        let selectors = [
-            (Selector::parse("a[href]").unwrap(), "href"),
-            (Selector::parse("link[href]").unwrap(), "href"),
-            (Selector::parse("img[src]").unwrap(), "src"),
-            (Selector::parse("script[src]").unwrap(), "src"),
-            (Selector::parse("link[rel='stylesheet']").unwrap(), "href"),
-            (Selector::parse("style").unwrap(), "textContent"),
-            (Selector::parse("[style]").unwrap(), "style"), // Pour les styles inline
-        ];
-
-        let mut processed_html = html.to_string();
-
-        for (selector, attr) in selectors.iter() {
-            for element in document.select(selector) {
-                // Traitement spécial pour les balises style et attributs style
-                if *attr == "textContent" || *attr == "style" {
-                    let css_content = if *attr == "textContent" {
-                        element.inner_html()
-                    } else {
-                        element.value().attr(attr).unwrap_or("").to_string()
-                    };
+        if self.logger.verbosity() == Verbosity::NoVerbose {
+            self.logger
+                .notice(&format!("URL:{} -> \"{}\"", url, full_path.display()));
+        }
 
-                    // Chercher toutes les URLs dans le CSS
-                    for cap in bg_regex.captures_iter(&css_content) {
-                        if let Some(url_match) = cap.get(1) {
-                            let url = url_match.as_str();
-                            if let Ok(absolute_url) = base_url.join(url) {
-                                //println!("Found background image: {}", url);
-                                let url_str = absolute_url.as_str();
-
-                                if absolute_url.host() == base_url.host() {
-                                    self.queue.push_back(url_str.to_string());
-                                }
-
-                                if self.convert_links {
-                                    let relative_path = self
-                                        .get_relative_path(url_str)
-                                        .unwrap_or_else(|_| url.to_string().into());
-                                    processed_html = processed_html
-                                        .replace(url, relative_path.to_str().unwrap_or(url));
-                                }
-                            }
-                        }
-                    }
-                } else {
-                    // Traitement normal des autres attributs
-                    if let Some(link) = element.value().attr(attr) {
-                        if let Ok(absolute_url) = base_url.join(link) {
-                            //println!("Found src: {}", link);
-                            let url_str = absolute_url.as_str();
-
-                            if absolute_url.host() == base_url.host() {
-                                self.queue.push_back(url_str.to_string());
-                            }
-
-                            if self.convert_links {
-                                let relative_path = self
-                                    .get_relative_path(url_str)
-                                    .unwrap_or_else(|_| link.to_string().into());
-                                processed_html = processed_html
-                                    .replace(link, relative_path.to_str().unwrap_or(link));
-                            }
-                        }
-                    }
+        Ok(links)
+    }
+
+    /// Writes the body chunk by chunk as it arrives, within `--rate-limit`.
+    /// Returns false when the body turns out to be over `--max-file-size`,
+    /// for servers that don't send `Content-Length`.
+    async fn stream_to_file(
+        &self,
+        url: &str,
+        response: reqwest::Response,
+        file: &mut File,
+        path: &Path,
+    ) -> WgetResult<bool> {
+        let pb = self.progress.as_ref().map(|mp| {
+            let pb = mp.add(utils::create_progress_bar(
+                response.content_length().unwrap_or(0),
+            ));
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            pb.set_prefix(format!("[{}]", name));
+            pb
+        });
+
+        let mut downloaded: u64 = 0;
+        let mut stream = response.bytes_stream();
+        while let Some(item) = stream.next().await {
+            let chunk = item?;
+            downloaded += chunk.len() as u64;
+            if self.max_file_size.is_some_and(|max| downloaded > max) {
+                if let Some(pb) = &pb {
+                    pb.finish_and_clear();
                 }
+                self.logger
+                    .notice(&format!("Skipping {} (over --max-file-size)", url));
+                return Ok(false);
+            }
+            if let Some(limiter) = &self.rate_limiter {
+                limiter.consume(chunk.len() as u64).await;
+            }
+            file.write_all(&chunk).await?;
+            if let Some(pb) = &pb {
+                pb.set_position(downloaded);
             }
         }
 
-        Ok(processed_html)
+        if let Some(pb) = &pb {
+            pb.finish_and_clear();
+        }
+        Ok(true)
     }
 
-    fn should_exclude(&self, url: &str) -> bool {
-        // check the extensions rejected
-        if let Ok(url) = Url::parse(url) {
-            if let Some(path) = url.path_segments() {
-                if let Some(last) = path.last() {
-                    if let Some(ext) = Path::new(last).extension() {
-                        if let Some(ext_str) = ext.to_str() {
-                            if self.rejected_extensions.contains(ext_str) {
-                                return true;
-                            }
-                        }
-                    }
+    /// Returns the links of the page to crawl next.
+    fn process_html(&self, html: &str, base_url: &str, depth: u32) -> WgetResult<Vec<String>> {
+        let page_url = Url::parse(base_url)?;
+        // relative links are resolved against `<base href>` when there is one
+        let base_url = base_href(html)
+            .and_then(|href| page_url.join(&href).ok())
+            .unwrap_or_else(|| page_url.clone());
+        let mut links = Vec::new();
+        let follow = self.max_depth.is_none_or(|max| depth < max);
+        // with -p the requisites of the last level are still fetched so its
+        // pages render
+        let follow_requisites =
+            follow || (self.page_requisites && self.max_depth.is_none_or(|max| depth <= max));
+
+        for Link {
+            url: link,
+            nofollow,
+            requisite,
+        } in extract_links(html)
+        {
+            if let Ok(absolute_url) = base_url.join(&link) {
+                self.logger.debug(&format!("Found link: {}", link));
+
+                let same_host = absolute_url.host() == page_url.host();
+                let allowed = same_host || self.host_allowed(&absolute_url);
+                let skip = nofollow && self.robots;
+                let within_depth = if requisite { follow_requisites } else { follow };
+                if within_depth && !skip && allowed && !self.should_exclude(&absolute_url) {
+                    links.push(without_fragment(&absolute_url));
                 }
             }
+        }
+
+        Ok(links)
+    }
 
-            // check the excluded paths
-            for excluded in &self.excluded_paths {
-                if url.path().starts_with(excluded) {
-                    return true;
+    /// Returns the fonts, images and `@import`ed stylesheets of a stylesheet
+    /// to fetch. With `-p` they are needed to render the pages using the
+    /// stylesheet, so like any page requisite they don't count against
+    /// `--level`. Without it they are links like any other.
+    fn process_css(&self, css: &str, base_url: &str, depth: u32) -> WgetResult<Vec<String>> {
+        let base_url = Url::parse(base_url)?;
+        let mut links = Vec::new();
+        if !self.page_requisites && self.max_depth.is_some_and(|max| depth >= max) {
+            return Ok(links);
+        }
+
+        for reference in css::extract_urls(css) {
+            if let Ok(absolute_url) = base_url.join(&reference) {
+                self.logger.debug(&format!("Found link: {}", reference));
+                let allowed =
+                    absolute_url.host() == base_url.host() || self.host_allowed(&absolute_url);
+                if allowed && !self.should_exclude(&absolute_url) {
+                    links.push(without_fragment(&absolute_url));
                 }
             }
         }
 
+        Ok(links)
+    }
+
+    /// What `--convert-links` turns a link of `page_url` into, like GNU
+    /// wget does: links to saved files point to the local copy, relative to
+    /// the page's own file, and links to anything else become absolute URLs.
+    /// Links within the page (`#top`) and non-HTTP links are left as they
+    /// are.
+    fn convert_link(&self, page_url: &Url, base_url: &Url, link: &str) -> Option<String> {
+        if link.starts_with('#') && base_url == page_url {
+            return None;
+        }
+        let absolute_url = base_url.join(link).ok()?;
+        if absolute_url.scheme() != "http" && absolute_url.scheme() != "https" {
+            return None;
+        }
+
+        let target_url =
+            self.redirect_destination(normalize_url(&absolute_url, self.canonicalize_urls));
+        let saved_files = utils::lock(&self.saved_files);
+        let (Some(target), Some(page)) = (
+            saved_files.get(&target_url),
+            saved_files.get(&self.url_key(page_url.as_str())),
+        ) else {
+            return Some(absolute_url.to_string());
+        };
+        let from = page.path.parent().unwrap_or(Path::new(""));
+
+        let mut link = relative_path(from, &target.path)
+            .components()
+            .map(|component| component.as_os_str().to_str().map(filename::link_component))
+            .collect::<Option<Vec<_>>>()?
+            .join("/");
+        if let Some(fragment) = absolute_url.fragment() {
+            link.push('#');
+            link.push_str(fragment);
+        }
+        Some(link)
+    }
+
+    /// What tells URLs apart when deduplicating, see [`normalize_url`].
+    fn url_key(&self, url: &str) -> String {
+        match Url::parse(url) {
+            Ok(url) => normalize_url(&url, self.canonicalize_urls),
+            Err(_) => url.to_string(),
+        }
+    }
+
+    /// Where a URL ended up after its redirects, itself if it had none.
+    fn redirect_destination(&self, mut url: String) -> String {
+        let redirects = utils::lock(&self.redirects);
+        for _ in 0..utils::MAX_REDIRECTS {
+            match redirects.get(&url) {
+                Some(target) => url = target.clone(),
+                None => break,
+            }
+        }
+        url
+    }
+
+    /// A redirect is followed when a link to its target would be.
+    fn redirect_allowed(&self, target: &Url) -> bool {
+        self.host_allowed(target) && !self.should_exclude(target)
+    }
+
+    /// Whether links to this URL's host are followed: the start host (and
+    /// the hosts it redirected to) always,
+    /// other hosts only with `-H` and within `--domains`, never those in
+    /// `--exclude-domains`. Domains match the host and its subdomains.
+    fn host_allowed(&self, url: &Url) -> bool {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return false;
+        }
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+
+        let in_domain = |domain: &String| {
+            let domain = domain.trim_start_matches('.');
+            host == domain || host.ends_with(&format!(".{}", domain))
+        };
+
+        if self.excluded_domains.iter().any(in_domain) {
+            return false;
+        }
+        if host == self.start_host || utils::lock(&self.start_aliases).contains(host) {
+            return true;
+        }
+        self.span_hosts && (self.domains.is_empty() || self.domains.iter().any(in_domain))
+    }
+
+    /// Where a URL is saved: `<host>/<path>` under `-P`, see
+    /// [`DirectoryLayout`].
+    fn local_path(&self, url: &str) -> WgetResult<PathBuf> {
+        let parsed_url = Url::parse(url)?;
+        if parsed_url.host_str().is_none() {
+            return Err("Invalid URL: no host".into());
+        }
+        Ok(self.output_dir.join(
+            self.layout
+                .relative_path(&parsed_url, &self.get_relative_path(url)?),
+        ))
+    }
+
+    /// Files that only `-nd`, `-nH` with `-H` or `--cut-dirs` put in the
+    /// same place: the first one keeps the name, the next ones get a `.1`,
+    /// `.2`... suffix like GNU wget does. URLs with the same `key` (`/` and
+    /// `/index.html`) share the file.
+    fn claim_path(&self, key: &str, path: PathBuf) -> PathBuf {
+        let mut claimed = utils::lock(&self.claimed_paths);
+        let mut candidate = path.clone();
+        let mut index = 1;
+        while claimed.get(&candidate).is_some_and(|owner| owner != key) {
+            candidate = append_to_path(&path, &format!(".{}", index));
+            index += 1;
+        }
+        claimed.insert(candidate.clone(), key.to_string());
+        candidate
+    }
+
+    /// Where a URL would be saved with every directory kept, plus the `-E`
+    /// extension: files with the same key are the same file whatever the
+    /// layout.
+    fn path_key(&self, url: &str, extension: &str) -> WgetResult<String> {
+        let parsed_url = Url::parse(url)?;
+        Ok(format!(
+            "{}://{}:{}/{}{}",
+            parsed_url.scheme(),
+            parsed_url.host_str().unwrap_or_default(),
+            parsed_url.port_or_known_default().unwrap_or_default(),
+            self.get_relative_path(url)?.display(),
+            extension
+        ))
+    }
+
+    /// Filters applied to a link before it is queued. The start URL is
+    /// never filtered.
+    fn should_exclude(&self, url: &Url) -> bool {
+        // rejected pages are still crawled to find the files
+        if !self.filter.is_wanted(url) && !filter::looks_like_page(url) {
+            return true;
+        }
+
+        // check the excluded and included directories
+        let path = if self.filter.ignore_case() {
+            url.path().to_lowercase()
+        } else {
+            url.path().to_string()
+        };
+        let path = path.as_str();
+        if self.excluded_paths.iter().any(|dir| dir_matches(dir, path)) {
+            return true;
+        }
+        if !self.included_paths.is_empty()
+            && !self.included_paths.iter().any(|dir| dir_matches(dir, path))
+        {
+            return true;
+        }
+
+        // --no-parent only restricts the starting host; it compares the
+        // path as written, --ignore-case is for -X/-I
+        if let Some(parent) = &self.no_parent_dir {
+            if url.host_str() == Some(self.start_host.as_str()) && !url.path().starts_with(parent) {
+                return true;
+            }
+        }
+
         false
     }
 
@@ -218,11 +1126,325 @@ impl WebsiteMirror {
             "/index.html".to_string()
         } else if path.ends_with('/') {
             format!("{}index.html", path)
-        } else if !path.contains('.') {
+        } else if !path.contains('.') && !self.adjust_extension && url.query().is_none() {
+            // without -E the type is unknown, guess a directory index; with
+            // a query it's a script, `list?page=1` like GNU wget
             format!("{}/index.html", path)
         } else {
             path.to_string()
         };
-        Ok(PathBuf::from(&path[1..]))
+        Ok(self.file_names.file_path(&path[1..], url.query()))
+    }
+}
+
+/// What `--resume-crawl` needs to pick up an interrupted crawl, saved as
+/// JSON next to the mirror.
+#[derive(Serialize, Deserialize)]
+struct MirrorState {
+    start_url: String,
+    depth: u32,
+    /// URLs of the level being crawled that were not fetched yet
+    pending: Vec<String>,
+    /// links found by the URLs of the level that were
+    found: Vec<String>,
+    seen_urls: Vec<String>,
+    saved_files: HashMap<String, SavedFile>,
+    claimed_paths: Vec<(PathBuf, String)>,
+    redirects: HashMap<String, String>,
+    redirect_targets: Vec<String>,
+    start_aliases: Vec<String>,
+    failures: Vec<(String, String)>,
+}
+
+/// One state file per start URL, so several mirrors can share `-P`.
+fn state_file_name(start_url: &str) -> String {
+    let hash: String = Sha256::digest(start_url.as_bytes())
+        .iter()
+        .take(4)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(".wget-crawl-{}.json", hash)
+}
+
+/// The absolute URL a 3xx response points to, `None` for any other
+/// response (including 304) or a redirect without a usable `Location`.
+fn redirect_target(response: &reqwest::Response, url: &Url) -> Option<Url> {
+    if !response.status().is_redirection() || response.status() == StatusCode::NOT_MODIFIED {
+        return None;
+    }
+    let location = response.headers().get(LOCATION)?.to_str().ok()?;
+    url.join(location).ok()
+}
+
+/// A URL as it is queued and fetched: as written, without the `#fragment`
+/// that never reaches the server.
+fn without_fragment(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url.to_string()
+}
+
+/// The key under which a URL is deduplicated and its file looked up, so
+/// that `page`, `page#top` and `HTTP://Example.com:80/a/../page?` are the
+/// same URL. Parsing already lowercases the scheme and host, drops the
+/// default port and resolves dot segments; this also drops the fragment and
+/// an empty query, and normalizes percent-escapes. With `canonical`
+/// (`--canonicalize-urls`) the query parameters are sorted and extensionless
+/// paths get the trailing slash of the directory they are saved as. The key
+/// is never requested, the server may not agree with it.
+fn normalize_url(url: &Url, canonical: bool) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+
+    let path = normalize_escapes(url.path());
+    url.set_path(&path);
+    let mut query = url.query().map(normalize_escapes);
+    if canonical {
+        query = query.map(|query| {
+            let mut params: Vec<&str> = query.split('&').filter(|p| !p.is_empty()).collect();
+            params.sort();
+            params.join("&")
+        });
+        let last = path.rsplit('/').next().unwrap_or("");
+        if !last.is_empty() && !last.contains('.') {
+            url.set_path(&format!("{}/", path));
+        }
+    }
+    url.set_query(query.as_deref().filter(|query| !query.is_empty()));
+
+    url.to_string()
+}
+
+/// `%2f` and `%2F` are the same character, and `%61` is just `a`:
+/// escapes are uppercased and unreserved characters decoded.
+fn normalize_escapes(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find('%') {
+        result.push_str(&rest[..pos]);
+        let escape = rest.get(pos + 1..pos + 3);
+        match escape.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                result.push(byte as char);
+                rest = &rest[pos + 3..];
+            }
+            Some(_) => {
+                result.push('%');
+                result.push_str(&escape.unwrap_or_default().to_ascii_uppercase());
+                rest = &rest[pos + 3..];
+            }
+            None => {
+                result.push('%');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Where `-K` keeps the original of a converted file.
+fn orig_path(path: &Path) -> PathBuf {
+    append_to_path(path, ".orig")
+}
+
+/// `about` + `.html` is `about.html`.
+fn append_to_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Path of `to` relative to the directory `from`, both relative to the
+/// same root: `relative_path("blog/post", "css/site.css")` is
+/// `../../css/site.css`.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &to[common..] {
+        path.push(component);
+    }
+    path
+}
+
+/// `-X`/`-I` entries, lowercased for `--ignore-case` (paths are too).
+fn dir_list(dirs: HashSet<String>, ignore_case: bool) -> HashSet<String> {
+    if ignore_case {
+        dirs.into_iter().map(|dir| dir.to_lowercase()).collect()
+    } else {
+        dirs
+    }
+}
+
+/// Whether a URL path is inside a `-X`/`-I` directory. Plain entries are
+/// prefixes (`/docs`), entries with wildcards (`/docs/*/old`) must match the
+/// path's directory or one of its parents.
+fn dir_matches(dir: &str, path: &str) -> bool {
+    if !utils::has_wildcards(dir) {
+        return path.starts_with(dir);
+    }
+    let dir = dir.trim_end_matches('/');
+    let parent = &path[..path.rfind('/').unwrap_or(0)];
+    parent
+        .match_indices('/')
+        .map(|(index, _)| &parent[..index])
+        .chain(std::iter::once(parent))
+        .any(|candidate| utils::glob_match(dir, candidate))
+}
+
+fn is_html_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("html") | Some("htm")
+    )
+}
+
+fn is_css_file(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some("css")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn normalized(url: &str, canonical: bool) -> String {
+        normalize_url(&Url::parse(url).unwrap(), canonical)
+    }
+
+    #[test]
+    fn normalize_drops_fragment_and_empty_query() {
+        assert_eq!(
+            normalized("http://a.com/page#top", false),
+            "http://a.com/page"
+        );
+        assert_eq!(normalized("http://a.com/page?", false), "http://a.com/page");
+        assert_eq!(
+            normalized("http://a.com/page?#x", false),
+            "http://a.com/page"
+        );
+    }
+
+    #[test]
+    fn normalize_host_port_and_dot_segments() {
+        assert_eq!(
+            normalized("HTTP://Example.COM:80/docs/../a/./page", false),
+            "http://example.com/a/page"
+        );
+        assert_eq!(normalized("https://a.com:443/", false), "https://a.com/");
+        assert_eq!(
+            normalized("http://a.com:8080/", false),
+            "http://a.com:8080/"
+        );
+    }
+
+    #[test]
+    fn normalize_percent_escapes() {
+        assert_eq!(
+            normalized("http://a.com/%61bout", false),
+            "http://a.com/about"
+        );
+        assert_eq!(
+            normalized("http://a.com/a%2fb", false),
+            "http://a.com/a%2Fb"
+        );
+        assert_eq!(
+            normalized("http://a.com/?q=%7e%20", false),
+            "http://a.com/?q=~%20"
+        );
+        assert_eq!(normalize_escapes("100%"), "100%");
+        assert_eq!(normalize_escapes("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn normalize_keeps_query_order_and_path_by_default() {
+        assert_eq!(
+            normalized("http://a.com/list?b=2&a=1", false),
+            "http://a.com/list?b=2&a=1"
+        );
+        assert_eq!(normalized("http://a.com/docs", false), "http://a.com/docs");
+    }
+
+    #[test]
+    fn canonical_sorts_query_and_adds_trailing_slash() {
+        assert_eq!(
+            normalized("http://a.com/list.html?b=2&a=1&", true),
+            "http://a.com/list.html?a=1&b=2"
+        );
+        assert_eq!(normalized("http://a.com/docs", true), "http://a.com/docs/");
+        assert_eq!(normalized("http://a.com/docs/", true), "http://a.com/docs/");
+        assert_eq!(
+            normalized("http://a.com/a.html", true),
+            "http://a.com/a.html"
+        );
+        assert_eq!(
+            normalized("http://a.com/list?page=1", true),
+            "http://a.com/list/?page=1"
+        );
+    }
+
+    #[test]
+    fn relative_paths() {
+        let relative = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+        assert_eq!(
+            relative("site/blog/post", "site/css/a.css"),
+            Path::new("../../css/a.css")
+        );
+        assert_eq!(relative("site", "site/index.html"), Path::new("index.html"));
+        assert_eq!(relative("site/a", "site/a/b/c.png"), Path::new("b/c.png"));
+        assert_eq!(relative("site/a/b", "site/a"), Path::new(".."));
+        assert_eq!(relative("", "x.html"), Path::new("x.html"));
+    }
+
+    #[test]
+    fn fetched_url_only_loses_its_fragment() {
+        let url = Url::parse("http://a.com/about?b=2&a=1#top").unwrap();
+        assert_eq!(without_fragment(&url), "http://a.com/about?b=2&a=1");
+        let url = Url::parse("http://a.com/%61bout").unwrap();
+        assert_eq!(without_fragment(&url), "http://a.com/%61bout");
+    }
+
+    fn mirror(args: &[&str]) -> WebsiteMirror {
+        let args = Args::parse_from(args);
+        let logger = Logger::stdout().with_verbosity(Verbosity::Quiet);
+        WebsiteMirror::new(args.urls[0].clone(), &args, logger).unwrap()
+    }
+
+    #[test]
+    fn query_file_names() {
+        let mirror = mirror(&["wget", "-r", "http://a.com/"]);
+        let path = |url| mirror.get_relative_path(url).unwrap();
+        assert_eq!(path("http://a.com/list?page=1"), Path::new("list?page=1"));
+        assert_eq!(path("http://a.com/list"), Path::new("list/index.html"));
+        assert_eq!(path("http://a.com/?page=1"), Path::new("index.html?page=1"));
+        assert_eq!(path("http://a.com/docs/"), Path::new("docs/index.html"));
+    }
+
+    #[tokio::test]
+    async fn file_shared_by_two_urls_is_converted_once() {
+        let dir = std::env::temp_dir().join(format!("wget-convert-{}", std::process::id()));
+        let page = dir.join("index.html");
+        let original = r#"<a href="/blog/">blog</a>"#;
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&page, original).unwrap();
+
+        let mirror = mirror(&["wget", "-r", "--convert-links", "-K", "http://a.com/"]);
+        mirror.record_saved_file("http://a.com/", &page, FileKind::Html, true);
+        mirror.record_saved_file("http://a.com/index.html", &page, FileKind::Html, true);
+        let blog = dir.join("blog").join("index.html");
+        mirror.record_saved_file("http://a.com/blog/", &blog, FileKind::Other, true);
+        mirror.convert_saved_files().await.unwrap();
+
+        let converted = std::fs::read_to_string(&page).unwrap();
+        let backup = std::fs::read_to_string(orig_path(&page)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(converted, r#"<a href="blog/index.html">blog</a>"#);
+        assert_eq!(backup, original);
     }
 }
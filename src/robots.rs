@@ -0,0 +1,284 @@
+use scraper::{Html, Selector};
+
+/// Product token we look for in `User-agent` lines.
+const ROBOTS_AGENT: &str = "wget";
+
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<f64>,
+}
+
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+/// The rules of a `robots.txt` that apply to us.
+#[derive(Default)]
+pub struct RobotsRules {
+    rules: Vec<Rule>,
+    pub crawl_delay: Option<f64>,
+}
+
+impl RobotsRules {
+    /// Everything allowed, used when the site has no usable `robots.txt`.
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Keeps the groups naming `wget`, or the `*` groups if none does.
+    pub fn parse(content: &str) -> Self {
+        let mut groups: Vec<Group> = Vec::new();
+        let mut in_agent_lines = false;
+
+        for line in content.lines() {
+            // strip comments
+            let line = line.split('#').next().unwrap_or("").trim();
+            let Some((field, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+
+            match field.trim().to_lowercase().as_str() {
+                "user-agent" => {
+                    // consecutive user-agent lines share the same group
+                    if !in_agent_lines {
+                        groups.push(Group {
+                            agents: Vec::new(),
+                            rules: Vec::new(),
+                            crawl_delay: None,
+                        });
+                    }
+                    if let Some(group) = groups.last_mut() {
+                        group.agents.push(value.to_lowercase());
+                    }
+                    in_agent_lines = true;
+                }
+                field @ ("allow" | "disallow") => {
+                    in_agent_lines = false;
+                    // an empty Disallow allows everything, same as no rule
+                    if value.is_empty() {
+                        continue;
+                    }
+                    if let Some(group) = groups.last_mut() {
+                        group.rules.push(Rule {
+                            allow: field == "allow",
+                            pattern: value.to_string(),
+                        });
+                    }
+                }
+                "crawl-delay" => {
+                    in_agent_lines = false;
+                    if let Some(group) = groups.last_mut() {
+                        group.crawl_delay = value.parse().ok();
+                    }
+                }
+                _ => in_agent_lines = false,
+            }
+        }
+
+        let named = |group: &Group| group.agents.iter().any(|agent| agent == ROBOTS_AGENT);
+        let wildcard = |group: &Group| group.agents.iter().any(|agent| agent == "*");
+        let selector: &dyn Fn(&Group) -> bool = if groups.iter().any(named) {
+            &named
+        } else {
+            &wildcard
+        };
+
+        let mut robots = Self::default();
+        for group in groups.into_iter().filter(|g| selector(g)) {
+            robots.crawl_delay = robots.crawl_delay.or(group.crawl_delay);
+            robots.rules.extend(group.rules);
+        }
+        robots
+    }
+
+    /// The most specific (longest) matching rule wins, `Allow` on a tie.
+    /// `path` includes the query string, as in `/search?q=x`.
+    pub fn is_allowed(&self, path: &str) -> bool {
+        let mut best: Option<&Rule> = None;
+        for rule in self
+            .rules
+            .iter()
+            .filter(|r| pattern_matches(&r.pattern, path))
+        {
+            best = match best {
+                Some(current)
+                    if current.pattern.len() > rule.pattern.len()
+                        || (current.pattern.len() == rule.pattern.len() && current.allow) =>
+                {
+                    Some(current)
+                }
+                _ => Some(rule),
+            };
+        }
+        best.is_none_or(|rule| rule.allow)
+    }
+}
+
+/// Matches a robots.txt path pattern: a prefix where `*` stands for any
+/// sequence of characters and a trailing `$` anchors the end of the path.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = path.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    if parts.is_empty() {
+        return !anchored || rest.is_empty();
+    }
+
+    for (index, part) in parts.iter().enumerate() {
+        // the `*` before the last part absorbs whatever comes before it
+        if anchored && index == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Directives of `<meta name="robots">` tags.
+#[derive(Default, Clone, Copy)]
+pub struct MetaRobots {
+    /// don't keep the page
+    pub noindex: bool,
+    /// don't follow any link of the page
+    pub nofollow: bool,
+}
+
+pub fn meta_directives(html: &str) -> MetaRobots {
+    let document = Html::parse_document(html);
+    let selector = Selector::parse("meta[name][content]").unwrap();
+    let mut meta = MetaRobots::default();
+
+    for element in document.select(&selector) {
+        let name = element.value().attr("name").unwrap_or("");
+        if !name.eq_ignore_ascii_case("robots") {
+            continue;
+        }
+        let content = element.value().attr("content").unwrap_or("");
+        for directive in content.split(',').map(|d| d.trim().to_lowercase()) {
+            match directive.as_str() {
+                "noindex" => meta.noindex = true,
+                "nofollow" => meta.nofollow = true,
+                "none" => {
+                    meta.noindex = true;
+                    meta.nofollow = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    meta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_prefix() {
+        assert!(pattern_matches("/private", "/private"));
+        assert!(pattern_matches("/private", "/private/a.html"));
+        assert!(pattern_matches("/private", "/private-notes"));
+        assert!(!pattern_matches("/private", "/public/private"));
+    }
+
+    #[test]
+    fn pattern_wildcards() {
+        assert!(pattern_matches("/*/old", "/docs/old/a.html"));
+        assert!(pattern_matches("/a*b*c", "/axxbyyc"));
+        assert!(!pattern_matches("/a*b*c", "/axxcyyb"));
+        assert!(pattern_matches("/*?", "/search?q=x"));
+        assert!(!pattern_matches("/*?", "/search"));
+    }
+
+    #[test]
+    fn pattern_end_anchor() {
+        assert!(pattern_matches("/*.php$", "/index.php"));
+        assert!(pattern_matches("/*.php$", "/a/b.php"));
+        assert!(!pattern_matches("/*.php$", "/index.php?x=1"));
+        assert!(!pattern_matches("/*.php$", "/index.php5"));
+        assert!(pattern_matches("/exact$", "/exact"));
+        assert!(!pattern_matches("/exact$", "/exact/"));
+        assert!(pattern_matches("/a*$", "/anything"));
+    }
+
+    #[test]
+    fn longest_rule_wins() {
+        let rules = RobotsRules::parse(
+            "User-agent: *\nDisallow: /docs/\nAllow: /docs/public/\nDisallow: /docs/public/secret\n",
+        );
+        assert!(rules.is_allowed("/"));
+        assert!(!rules.is_allowed("/docs/a.html"));
+        assert!(rules.is_allowed("/docs/public/a.html"));
+        assert!(!rules.is_allowed("/docs/public/secret.html"));
+    }
+
+    #[test]
+    fn allow_wins_a_tie() {
+        let rules = RobotsRules::parse("User-agent: *\nDisallow: /page\nAllow: /page\n");
+        assert!(rules.is_allowed("/page"));
+    }
+
+    #[test]
+    fn wildcard_and_anchor_rules() {
+        let rules = RobotsRules::parse("User-agent: *\nDisallow: /*.pdf$\nDisallow: /*?sort=\n");
+        assert!(!rules.is_allowed("/papers/a.pdf"));
+        assert!(rules.is_allowed("/papers/a.pdf?download=1"));
+        assert!(!rules.is_allowed("/list?sort=name"));
+        assert!(rules.is_allowed("/list?page=2"));
+    }
+
+    #[test]
+    fn wget_group_replaces_wildcard_group() {
+        let rules = RobotsRules::parse(
+            "User-agent: *\nDisallow: /\n\nUser-agent: Googlebot\nUser-agent: Wget\nDisallow: /tmp/\nCrawl-delay: 2\n",
+        );
+        assert!(rules.is_allowed("/index.html"));
+        assert!(!rules.is_allowed("/tmp/a"));
+        assert_eq!(rules.crawl_delay, Some(2.0));
+    }
+
+    #[test]
+    fn empty_or_partial_agents_are_not_wget() {
+        for agent in ["", "w", "get", "wge"] {
+            let rules = RobotsRules::parse(&format!(
+                "User-agent: {}\nDisallow:\n\nUser-agent: *\nDisallow: /blog/\n",
+                agent
+            ));
+            assert!(!rules.is_allowed("/blog/post/"), "agent {:?}", agent);
+        }
+        let rules = RobotsRules::parse("User-agent: WGET\nDisallow: /tmp/\n");
+        assert!(!rules.is_allowed("/tmp/a"));
+    }
+
+    #[test]
+    fn empty_disallow_and_comments() {
+        let rules = RobotsRules::parse("# all welcome\nUser-agent: *\nDisallow:   # nothing\n");
+        assert!(rules.is_allowed("/anything"));
+    }
+
+    #[test]
+    fn meta_robots() {
+        let meta = meta_directives(r#"<meta name="ROBOTS" content="noindex, NOFOLLOW">"#);
+        assert!(meta.noindex && meta.nofollow);
+        let meta = meta_directives(r#"<meta name="robots" content="none">"#);
+        assert!(meta.noindex && meta.nofollow);
+        let meta = meta_directives(r#"<meta name="description" content="nofollow">"#);
+        assert!(!meta.noindex && !meta.nofollow);
+    }
+}